@@ -0,0 +1,269 @@
+use std::{fmt, sync::Arc};
+
+use async_trait::async_trait;
+
+use crate::{
+    candidate::Candidate,
+    cloud_pinyin_client::CloudPinyinClient,
+    config::{BackendKind, Config, DictionaryConfig},
+    input_tool::InputTool,
+    local_dictionary::LocalDictionary,
+    query_cache::QueryCache,
+};
+
+// Anything that can turn a preedit into candidates. The dispatcher only talks to this trait, so
+// the cloud client can be replaced by another provider, a local engine or a fake.
+#[async_trait]
+pub trait CandidateBackend: Send + Sync {
+    async fn query_candidates(
+        &self,
+        preedit: &str,
+        depth: usize,
+    ) -> Result<Vec<Candidate>, QueryError>;
+
+    // Identifies what the candidates are for (e.g. the Input Tools code), so cached answers of one
+    // input tool are never served for another.
+    fn input_tool(&self) -> &str;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryError {
+    Timeout,
+    Dns(String),
+    Connection(String),
+    InvalidRequest(String),
+    HttpStatus(u16),
+    MalformedBody(String),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::Timeout => write!(f, "the request timed out"),
+            QueryError::Dns(e) => write!(f, "failed to resolve the host: {}", e),
+            QueryError::Connection(e) => write!(f, "connection problem: {}", e),
+            QueryError::InvalidRequest(e) => write!(f, "could not build the request: {}", e),
+            QueryError::HttpStatus(code) => write!(f, "the server responded with HTTP {}", code),
+            QueryError::MalformedBody(e) => write!(f, "malformed response body: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+// Everything a dispatcher can get candidates from: the configured backend, the on-disk cache in
+// front of it and the local dictionary behind it. Cheap to clone.
+#[derive(Clone)]
+pub struct Backends {
+    pub input_tool: InputTool,
+    pub primary: Arc<dyn CandidateBackend>,
+    pub cache: Option<Arc<QueryCache>>,
+    pub fallback: Option<Arc<LocalDictionary>>,
+}
+
+impl Backends {
+    // One set of backends per input tool. The cache and the dictionary are opened once and shared
+    // by all of them, as sled only allows a database to be opened once per process.
+    pub fn for_input_tools(config: &Config) -> Vec<(InputTool, Backends)> {
+        let cache = match config.backend {
+            BackendKind::Cloud => QueryCache::open(&config.cache).map(Arc::new),
+            _ => None,
+        };
+        let dictionary = LocalDictionary::open(&DictionaryConfig {
+            enabled: config.dictionary.enabled || config.backend == BackendKind::Local,
+            ..config.dictionary.clone()
+        })
+        .map(Arc::new);
+
+        InputTool::ALL
+            .into_iter()
+            .map(|tool| (tool, Backends::for_input_tool(config, tool, &cache, &dictionary)))
+            .collect()
+    }
+
+    fn for_input_tool(
+        config: &Config,
+        input_tool: InputTool,
+        cache: &Option<Arc<QueryCache>>,
+        dictionary: &Option<Arc<LocalDictionary>>,
+    ) -> Backends {
+        let cloud = || Arc::new(CloudPinyinClient::new(&config.cloud, input_tool));
+
+        match (config.backend, dictionary) {
+            (BackendKind::Cloud, _) => Backends {
+                input_tool,
+                primary: cloud(),
+                cache: cache.clone(),
+                // The bundled lexicon only knows simplified Mandarin.
+                fallback: match input_tool {
+                    InputTool::PinyinSimplified => dictionary.clone(),
+                    _ => None,
+                },
+            },
+            (BackendKind::Local, Some(dictionary)) => Backends {
+                input_tool,
+                primary: dictionary.clone(),
+                cache: None,
+                fallback: None,
+            },
+            (BackendKind::Local, None) => {
+                println!("The local dictionary is unavailable, using the cloud instead.");
+                Backends {
+                    input_tool,
+                    primary: cloud(),
+                    cache: None,
+                    fallback: None,
+                }
+            }
+            (BackendKind::Fixed, _) => Backends {
+                input_tool,
+                primary: Arc::new(FixedBackend {}),
+                cache: None,
+                fallback: None,
+            },
+        }
+    }
+    pub fn cached(&self, preedit: &str, depth: usize) -> Option<Vec<Candidate>> {
+        let input_tool = self.primary.input_tool();
+        self.cache.as_ref()?.get(preedit, depth, input_tool)
+    }
+
+    // Asks the backend, for when the cache had nothing fresh. If the backend fails, whatever a
+    // stale cache entry and the local dictionary have is merged and used instead.
+    pub async fn fetch(&self, preedit: &str, depth: usize) -> Result<Vec<Candidate>, QueryError> {
+        let input_tool = self.primary.input_tool();
+        let cache = self.cache.as_ref();
+
+        let e = match self.primary.query_candidates(preedit, depth).await {
+            Ok(candidates) => {
+                if let Some(cache) = cache {
+                    if !candidates.is_empty() {
+                        cache.insert(preedit, depth, input_tool, &candidates);
+                    }
+                }
+                return Ok(candidates);
+            }
+            Err(e) => e,
+        };
+
+        let mut candidates = cache
+            .and_then(|c| c.get_stale(preedit, depth, input_tool))
+            .unwrap_or_default();
+        if let Some(dictionary) = &self.fallback {
+            for candidate in dictionary.lookup(preedit, depth) {
+                if !candidates.iter().any(|c| c.word == candidate.word) {
+                    candidates.push(candidate);
+                }
+            }
+        }
+
+        if candidates.is_empty() {
+            Err(e)
+        } else {
+            println!("Backend failed ({}), using offline candidates for {}.", e, preedit);
+            Ok(candidates)
+        }
+    }
+}
+
+// Always answers with the same two words, whatever is typed. Useful to test the IBus side without
+// depending on the network or on the dictionary.
+pub struct FixedBackend {}
+
+#[async_trait]
+impl CandidateBackend for FixedBackend {
+    async fn query_candidates(
+        &self,
+        preedit: &str,
+        _depth: usize,
+    ) -> Result<Vec<Candidate>, QueryError> {
+        if preedit.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        Ok(vec![
+            Candidate {
+                word: "你好".to_owned(),
+                annotation: "".to_owned(),
+                matched_len: None,
+            },
+            Candidate {
+                word: "世界".to_owned(),
+                annotation: "".to_owned(),
+                matched_len: None,
+            },
+        ])
+    }
+
+    fn input_tool(&self) -> &str {
+        "fixed"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    // Answers with one candidate per letter, or fails, and counts how often it was asked.
+    struct FakeBackend {
+        fail: bool,
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl CandidateBackend for FakeBackend {
+        async fn query_candidates(
+            &self,
+            preedit: &str,
+            _depth: usize,
+        ) -> Result<Vec<Candidate>, QueryError> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            if self.fail {
+                return Err(QueryError::Timeout);
+            }
+            Ok(preedit
+                .chars()
+                .map(|c| Candidate {
+                    word: c.to_string(),
+                    annotation: "".to_owned(),
+                    matched_len: Some(1),
+                })
+                .collect())
+        }
+
+        fn input_tool(&self) -> &str {
+            "fake"
+        }
+    }
+
+    fn backends(fail: bool) -> (Backends, Arc<FakeBackend>) {
+        let fake = Arc::new(FakeBackend {
+            fail,
+            calls: AtomicUsize::new(0),
+        });
+        let backends = Backends {
+            input_tool: InputTool::PinyinSimplified,
+            primary: fake.clone(),
+            cache: None,
+            fallback: None,
+        };
+        (backends, fake)
+    }
+
+    #[tokio::test]
+    async fn queries_the_primary_backend() {
+        let (backends, fake) = backends(false);
+        let candidates = backends.fetch("ab", 11).await.expect("The fake doesn't fail.");
+        let words: Vec<&str> = candidates.iter().map(|c| c.word.as_str()).collect();
+        assert_eq!(words, ["a", "b"]);
+        assert_eq!(fake.calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn surfaces_the_error_without_offline_candidates() {
+        let (backends, _) = backends(true);
+        assert_eq!(backends.fetch("ab", 11).await.unwrap_err(), QueryError::Timeout);
+    }
+}
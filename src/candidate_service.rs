@@ -1,19 +1,52 @@
 use std::sync::Arc;
 
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex, MutexGuard};
 
-use super::{candidate::Candidate, ibus_proxy::IBusProxy, ibus_variants::IBusLookupTable};
+use super::{
+    candidate::Candidate,
+    config::LookupTableConfig,
+    ibus_proxy::EngineSignals,
+    mode_switcher::Hotkey,
+    ibus_variants::{IBusLookupTable, IBusPropList, IBusProperty},
+    script_converter::ScriptConverter,
+};
+
+static SCRIPT_PROP_KEY: &str = "script";
 
 struct State {
     candidates: Vec<Candidate>,
     page: usize,
+    // Highlighted candidate, relative to the current page.
+    cursor: usize,
+    // The user moved the cursor, so Enter commits the highlighted candidate.
+    navigated: bool,
+    // The backend returned fewer candidates than we asked for, so there are no more to load.
+    complete: bool,
+    // Generation of the query the candidates came from. Answers to older queries are ignored.
+    generation: u64,
+    // Candidates are shown and committed in traditional characters.
+    traditional: bool,
+    // A move that ran out of candidates, made once more have been loaded.
+    advance: Option<Advance>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Advance {
+    Page,
+    Cursor,
 }
 
 impl State {
-    pub fn new() -> Self {
+    pub fn new(traditional: bool) -> Self {
         State {
             candidates: Vec::new(),
             page: 0,
+            cursor: 0,
+            navigated: false,
+            complete: false,
+            generation: 0,
+            traditional,
+            advance: None,
         }
     }
 }
@@ -22,67 +55,119 @@ unsafe impl Sync for State {} // State is safe to share between threads
 
 pub struct CandidateService {
     lt_size: usize,
+    labels: Vec<char>,
+    orientation: i32,
+    round: bool,
+    annotations: bool,
     state: Mutex<State>,
-    ibus: Arc<Mutex<IBusProxy>>,
+    // The preedit changed and the candidates shown are still those for the old one.
+    stale: watch::Sender<bool>,
+    ibus: Arc<Mutex<dyn EngineSignals>>,
+    converter: Option<Arc<ScriptConverter>>,
+    // Shown in the tooltip of the 简/繁 button.
+    toggle_script: Option<Hotkey>,
 }
 
 impl CandidateService {
-    pub fn new(ibus: Arc<Mutex<IBusProxy>>) -> CandidateService {
+    pub fn new(
+        ibus: Arc<Mutex<dyn EngineSignals>>,
+        converter: Option<Arc<ScriptConverter>>,
+        traditional: bool,
+        toggle_script: Option<Hotkey>,
+        config: &LookupTableConfig,
+    ) -> CandidateService {
         CandidateService {
-            lt_size: 5,
-            state: Mutex::new(State::new()),
+            lt_size: config.page_size,
+            labels: config.labels.chars().collect(),
+            orientation: config.orientation.to_ibus(),
+            round: config.round,
+            annotations: config.annotations,
+            state: Mutex::new(State::new(traditional && converter.is_some())),
+            stale: watch::channel(false).0,
             ibus,
+            converter,
+            toggle_script,
         }
     }
 
     pub async fn in_session(&self) -> bool {
-        self.state.lock().await.candidates.len() != 0
+        !self.state.lock().await.candidates.is_empty()
     }
 
-    pub async fn set_candidates(&self, candidates: &[Candidate]) {
+    pub async fn set_candidates(&self, candidates: &[Candidate], complete: bool, generation: u64) {
         let mut state = self.state.lock().await;
+        if generation < state.generation {
+            return;
+        }
+        state.generation = generation;
+        state.complete = complete;
+        self.stale.send_replace(false);
 
         state.candidates.clear();
         for candidate in candidates {
             state.candidates.push(candidate.clone());
         }
+        let on_page = state
+            .candidates
+            .len()
+            .saturating_sub(state.page * self.lt_size)
+            .min(self.lt_size);
+        state.cursor = std::cmp::min(state.cursor, on_page.saturating_sub(1));
+
+        match state.advance.take() {
+            Some(Advance::Page) if (state.page + 1) * self.lt_size < state.candidates.len() => {
+                state.page += 1;
+                state.cursor = 0;
+            }
+            Some(Advance::Cursor) => {
+                Self::move_cursor_down(&mut state, self.lt_size);
+            }
+            _ => {}
+        }
 
-        // IBus
-        let page = state.page;
-        let start = 0 + self.lt_size * page; // inclusive
-        let end = start + self.lt_size; // exclusive
+        self.redraw(state).await;
+    }
 
-        let to_show = if state.candidates.is_empty() {
-            // 没有候选词，发送空表
-            IBusLookupTable::from_nothing()
-        } else 
-        {
-            // 计算实际的结束索引，防止越界
-            let actual_end = std::cmp::min(end, state.candidates.len());
-            // 只要 start < actual_end，就表示当前页有候选词
-            if start < actual_end {
-                IBusLookupTable::from_candidates(&state.candidates[start..actual_end])
-            } else {
-                // 理论上 state.page=0 时不会到这里，除非 lt_size=0
-                IBusLookupTable::from_nothing()
-            }
-        };
+    pub async fn advance_on_load(&self, advance: Advance) {
+        self.state.lock().await.advance = Some(advance);
+    }
+
+    // Shown as auxiliary text, with no candidates, when the backend could not be reached. The
+    // preedit is left alone so the user can keep typing, retry or commit the raw letters.
+    pub async fn set_offline(&self, generation: u64) {
+        let mut state = self.state.lock().await;
+        if generation < state.generation {
+            return;
+        }
+        state.generation = generation;
+        state.candidates.clear();
+        state.page = 0;
+        state.cursor = 0;
+        state.navigated = false;
+        state.complete = false;
+        state.advance = None;
+        self.stale.send_replace(false);
 
-        //println!("Candidates to show in table: {:?}", to_show); 
-        
         drop(state);
 
-        self.ibus
-            .lock()
-            .await
-            .update_lookup_table(to_show, true)
-            .await;
+        let ibus = self.ibus.lock().await;
+        ibus.update_lookup_table(IBusLookupTable::from_nothing(), false).await;
+        ibus.update_auxiliary_text("（离线）", true).await;
     }
 
     pub async fn page_into(&self) -> (bool, Option<usize>) {
         let mut state = self.state.lock().await;
 
         let potential_start = (state.page + 1) * self.lt_size;
+        if potential_start >= state.candidates.len() && state.complete {
+            // Nothing more to load: either go round to the first page or stay.
+            if self.round && state.page > 0 {
+                state.page = 0;
+                state.cursor = 0;
+                self.redraw(state).await;
+            }
+            return (true, None);
+        }
         if potential_start >= state.candidates.len() {
              // 需要更多候选词
             drop(state); // 释放锁
@@ -93,78 +178,450 @@ impl CandidateService {
 
         // 确认可以翻页
         state.page += 1;
-        let start = state.page * self.lt_size;
-        let end = start + self.lt_size;
-        let actual_end = std::cmp::min(end, state.candidates.len()); // 边界检查
-
-        let to_show = if start < actual_end {
-            IBusLookupTable::from_candidates(&state.candidates[start..actual_end])
-        } else {
-            // 理论上不应发生，因为上面检查过 potential_start
-            IBusLookupTable::from_nothing()
-        };
-
-        drop(state);
-
-        self.ibus
-            .lock()
-            .await
-            .update_lookup_table(to_show, true)
-            .await;
-        return (true, None);
+        state.cursor = 0;
+        self.redraw(state).await;
+        (true, None)
     }
 
     pub async fn page_back(&self) {
         let mut state = self.state.lock().await;
 
         if state.page == 0 {
-            drop(state); // 释放锁
+            let last_page = state.candidates.len().saturating_sub(1) / self.lt_size;
+            // Only once all candidates are loaded do we know which page is the last.
+            if self.round && state.complete && last_page > 0 {
+                state.page = last_page;
+                state.cursor = 0;
+                self.redraw(state).await;
+            }
             return; // 已经是第一页
         }
         state.page -= 1;
-        let start = state.page * self.lt_size;
-        let end = start + self.lt_size;
-        // 对于回翻，end 不会越界，因为之前的页肯定存在
-        let actual_end = std::cmp::min(end, state.candidates.len()); // 仍然做检查以防万一
+        state.cursor = 0;
+        self.redraw(state).await;
+    }
 
-        let to_show = if start < actual_end {
-            IBusLookupTable::from_candidates(&state.candidates[start..actual_end])
+    // Moves the highlight, wrapping onto the previous page. Returns false at the very first
+    // candidate.
+    pub async fn cursor_up(&self) -> bool {
+        let mut state = self.state.lock().await;
+        if state.cursor > 0 {
+            state.cursor -= 1;
+        } else if state.page > 0 {
+            state.page -= 1;
+            state.cursor = self.lt_size - 1;
+        } else if self.round && state.complete && state.candidates.len() > 1 {
+            let last = state.candidates.len() - 1;
+            state.page = last / self.lt_size;
+            state.cursor = last % self.lt_size;
         } else {
-            IBusLookupTable::from_nothing()
-        };
+            return false;
+        }
+        state.navigated = true;
+        self.redraw(state).await;
+        true
+    }
 
-        drop(state);
+    // Moves the highlight, wrapping onto the next page. Like page_into, returns how many
+    // candidates are needed when we have run out.
+    pub async fn cursor_down(&self) -> (bool, Option<usize>) {
+        let mut state = self.state.lock().await;
+        let next = state.page * self.lt_size + state.cursor + 1;
+        if next >= state.candidates.len() && state.complete {
+            if self.round && next > 1 {
+                state.page = 0;
+                state.cursor = 0;
+                state.navigated = true;
+                self.redraw(state).await;
+            }
+            return (true, None);
+        }
+        if next >= state.candidates.len() {
+            return (false, Some(next + 1));
+        }
+        Self::move_cursor_down(&mut state, self.lt_size);
+        self.redraw(state).await;
+        (true, None)
+    }
 
-        self.ibus
-            .lock()
-            .await
-            .update_lookup_table(to_show, true)
-            .await;
+    // One candidate further, onto the next page if need be. Stays put on the last candidate.
+    fn move_cursor_down(state: &mut State, lt_size: usize) {
+        if state.page * lt_size + state.cursor + 1 >= state.candidates.len() {
+            return;
+        }
+        if state.cursor + 1 < lt_size {
+            state.cursor += 1;
+        } else {
+            state.page += 1;
+            state.cursor = 0;
+        }
+        state.navigated = true;
+    }
+
+    // Whether the user moved the highlight since the candidates last changed.
+    pub async fn navigated(&self) -> bool {
+        self.state.lock().await.navigated
+    }
+
+    // Back to the first page with nothing highlighted but the top candidate. Called when the
+    // preedit changes, since the old position means nothing for the new candidates. The old
+    // candidates stay on screen but count as stale until those of the given generation, or a newer
+    // one, are set.
+    pub async fn rewind(&self, generation: u64) {
+        let mut state = self.state.lock().await;
+        state.generation = std::cmp::max(state.generation, generation);
+        state.page = 0;
+        state.cursor = 0;
+        state.navigated = false;
+        state.advance = None;
+        self.stale.send_replace(true);
     }
 
-    pub async fn select(&self, ith: usize) {
+    // Returns once the candidates match the preedit again, i.e. right away unless a query for a
+    // changed preedit is still in flight.
+    pub async fn wait_until_fresh(&self) {
+        let mut stale = self.stale.subscribe();
+        let _ = stale.wait_for(|stale| !*stale).await;
+    }
+
+    // The 1-based position on the page that the label selects.
+    pub fn label_position(&self, label: char) -> Option<usize> {
+        let i = self.labels.iter().take(self.lt_size).position(|l| *l == label)?;
+        Some(i + 1)
+    }
+
+    pub async fn select_highlighted(&self) -> Option<Candidate> {
+        let cursor = self.state.lock().await.cursor;
+        self.select(cursor + 1).await
+    }
+
+    // Commits the ith (1-based) candidate of the current page and returns it, so the caller can
+    // tell how much of the preedit it covered. Stale candidates can't be selected.
+    pub async fn select(&self, ith: usize) -> Option<Candidate> {
         let state = self.state.lock().await;
+        if *self.stale.borrow() || ith == 0 || ith > self.lt_size {
+            return None;
+        }
         let idx = ith - 1 + state.page * self.lt_size;
-        let text = state.candidates[idx].word.clone();
+        let candidate = state.candidates.get(idx)?.clone();
+        let text = self.to_script(&state, &candidate.word);
 
         drop(state);
 
         self.ibus.lock().await.commit_text(&text).await;
 
         self.clear().await;
+
+        Some(candidate)
+    }
+
+    // The highlighted candidate as it would be committed, without committing it.
+    pub async fn highlighted(&self) -> Option<Candidate> {
+        let state = self.state.lock().await;
+        let idx = state.page * self.lt_size + state.cursor;
+        let candidate = state.candidates.get(idx)?;
+        Some(Candidate {
+            word: self.to_script(&state, &candidate.word),
+            ..candidate.clone()
+        })
+    }
+
+    // The top candidate as it would be committed, without committing it. None while the
+    // candidates are stale, as they are for an older preedit.
+    pub async fn first(&self) -> Option<Candidate> {
+        let state = self.state.lock().await;
+        if *self.stale.borrow() {
+            return None;
+        }
+        let candidate = state.candidates.first()?;
+        Some(Candidate {
+            word: self.to_script(&state, &candidate.word),
+            ..candidate.clone()
+        })
     }
 
     pub async fn clear(&self) {
         let mut state = self.state.lock().await;
         state.candidates.clear();
         state.page = 0;
+        state.cursor = 0;
+        state.navigated = false;
+        state.complete = false;
+        state.advance = None;
+        self.stale.send_replace(false);
 
         drop(state);
 
+        let ibus = self.ibus.lock().await;
+        ibus.update_lookup_table(IBusLookupTable::from_nothing(), false).await;
+        ibus.update_auxiliary_text("", false).await;
+    }
+
+    pub async fn register_properties(&self) {
+        let traditional = self.state.lock().await.traditional;
+        let props = IBusPropList {
+            props: vec![self.script_property(traditional)],
+        };
+        self.ibus.lock().await.register_properties(props).await;
+    }
+
+    pub async fn property_activate(&self, name: &str) {
+        if name == SCRIPT_PROP_KEY {
+            self.toggle_traditional().await;
+        }
+    }
+
+    // Switches between simplified and traditional output and redraws the current page.
+    pub async fn toggle_traditional(&self) {
+        if self.converter.is_none() {
+            println!("No conversion tables were loaded, staying in simplified characters.");
+            return;
+        }
+
+        let mut state = self.state.lock().await;
+        state.traditional = !state.traditional;
+        let traditional = state.traditional;
+
+        if state.candidates.is_empty() {
+            drop(state);
+        } else {
+            self.redraw(state).await;
+        }
+
         self.ibus
             .lock()
             .await
-            .update_lookup_table(IBusLookupTable::from_nothing(), false)
+            .update_property(self.script_property(traditional))
             .await;
     }
+
+    // Sends the current page, and the pinyin of the highlighted candidate as auxiliary text.
+    async fn redraw(&self, state: MutexGuard<'_, State>) {
+        let start = state.page * self.lt_size;
+        let end = std::cmp::min(start + self.lt_size, state.candidates.len());
+        let to_show = if start < end {
+            self.to_lookup_table(&state, &state.candidates[start..end])
+        } else {
+            IBusLookupTable::from_nothing()
+        };
+        let aux = state
+            .candidates
+            .get(start + state.cursor)
+            .map(|cand| cand.annotation.clone())
+            .unwrap_or_default();
+
+        drop(state);
+
+        let ibus = self.ibus.lock().await;
+        ibus.update_lookup_table(to_show, true).await;
+        ibus.update_auxiliary_text(&aux, !aux.is_empty()).await;
+    }
+
+    fn to_lookup_table(&self, state: &State, cands: &[Candidate]) -> IBusLookupTable {
+        if !state.traditional && !self.annotations {
+            return IBusLookupTable::from_candidates(cands, state.cursor)
+                .with_layout(self.lt_size, &self.labels)
+                .with_style(self.orientation, self.round);
+        }
+
+        let converted: Vec<Candidate> = cands
+            .iter()
+            .map(|cand| {
+                let mut word = self.to_script(state, &cand.word);
+                if self.annotations && !cand.annotation.is_empty() {
+                    word = format!("{} {}", word, cand.annotation);
+                }
+                Candidate {
+                    word,
+                    ..cand.clone()
+                }
+            })
+            .collect();
+        IBusLookupTable::from_candidates(&converted, state.cursor)
+            .with_layout(self.lt_size, &self.labels)
+            .with_style(self.orientation, self.round)
+    }
+
+    fn to_script(&self, state: &State, word: &str) -> String {
+        match &self.converter {
+            Some(converter) if state.traditional => converter.convert(word),
+            _ => word.to_owned(),
+        }
+    }
+
+    fn script_property(&self, traditional: bool) -> IBusProperty {
+        let tooltip = match (&self.converter, &self.toggle_script) {
+            (Some(_), Some(hotkey)) => format!("Simplified/Traditional ({})", hotkey),
+            _ => "Simplified/Traditional".to_owned(),
+        };
+        IBusProperty {
+            key: SCRIPT_PROP_KEY.to_owned(),
+            prop_type: 1,
+            label: if traditional { "繁" } else { "简" }.to_owned(),
+            icon: "".to_owned(),
+            tooltip,
+            sensitive: true,
+            visible: true,
+            state: traditional as u32,
+            symbol: if traditional { "繁" } else { "简" }.to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ibus_proxy::recording::RecordingSignals;
+
+    // Three candidates per page.
+    fn service(round: bool) -> (CandidateService, RecordingSignals) {
+        let signals = RecordingSignals::default();
+        let config = LookupTableConfig {
+            page_size: 3,
+            round,
+            ..LookupTableConfig::default()
+        };
+        let ibus = Arc::new(Mutex::new(signals.clone()));
+        let service = CandidateService::new(ibus, None, false, None, &config);
+        (service, signals)
+    }
+
+    fn candidates(words: &str) -> Vec<Candidate> {
+        words
+            .chars()
+            .map(|c| Candidate {
+                word: c.to_string(),
+                annotation: "".to_owned(),
+                matched_len: None,
+            })
+            .collect()
+    }
+
+    fn page(signals: &RecordingSignals) -> (String, u32) {
+        let (words, cursor) = signals.page();
+        (words.concat(), cursor)
+    }
+
+    #[tokio::test]
+    async fn cursor_wraps_onto_the_next_page() {
+        let (service, signals) = service(false);
+        service.set_candidates(&candidates("abcdefg"), true, 1).await;
+
+        for _ in 0..3 {
+            assert_eq!(service.cursor_down().await, (true, None));
+        }
+        assert_eq!(page(&signals), ("def".to_owned(), 0));
+
+        assert!(service.cursor_up().await);
+        assert_eq!(page(&signals), ("abc".to_owned(), 2));
+        assert!(service.navigated().await);
+    }
+
+    #[tokio::test]
+    async fn round_goes_from_the_last_page_to_the_first_and_back() {
+        let (service, signals) = service(true);
+        service.set_candidates(&candidates("abcdefg"), true, 1).await;
+
+        service.page_back().await;
+        assert_eq!(page(&signals), ("g".to_owned(), 0));
+
+        assert_eq!(service.page_into().await, (true, None));
+        assert_eq!(page(&signals), ("abc".to_owned(), 0));
+
+        assert!(service.cursor_up().await);
+        assert_eq!(page(&signals), ("g".to_owned(), 0));
+        assert_eq!(service.cursor_down().await, (true, None));
+        assert_eq!(page(&signals), ("abc".to_owned(), 0));
+    }
+
+    #[tokio::test]
+    async fn without_round_paging_stops_at_both_ends() {
+        let (service, signals) = service(false);
+        service.set_candidates(&candidates("abcd"), true, 1).await;
+
+        service.page_back().await;
+        assert_eq!(page(&signals), ("abc".to_owned(), 0));
+
+        assert_eq!(service.page_into().await, (true, None));
+        assert_eq!(page(&signals), ("d".to_owned(), 0));
+        assert_eq!(service.page_into().await, (true, None));
+        assert_eq!(page(&signals), ("d".to_owned(), 0));
+        service.page_back().await;
+        assert!(!service.cursor_up().await);
+        assert_eq!(page(&signals), ("abc".to_owned(), 0));
+    }
+
+    #[tokio::test]
+    async fn round_waits_until_the_last_page_is_known() {
+        let (service, signals) = service(true);
+        service.set_candidates(&candidates("abcdef"), false, 1).await;
+
+        service.page_back().await;
+        assert_eq!(page(&signals), ("abc".to_owned(), 0));
+    }
+
+    #[tokio::test]
+    async fn page_down_lands_on_the_page_it_loaded() {
+        let (service, signals) = service(false);
+        service.set_candidates(&candidates("abc"), false, 1).await;
+
+        assert_eq!(service.page_into().await, (false, Some(4)));
+        service.advance_on_load(Advance::Page).await;
+        service.set_candidates(&candidates("abcdef"), false, 2).await;
+
+        assert_eq!(page(&signals), ("def".to_owned(), 0));
+    }
+
+    #[tokio::test]
+    async fn cursor_down_moves_onto_the_candidate_it_loaded() {
+        let (service, signals) = service(false);
+        service.set_candidates(&candidates("abc"), false, 1).await;
+        service.cursor_down().await;
+        service.cursor_down().await;
+
+        assert_eq!(service.cursor_down().await, (false, Some(4)));
+        service.advance_on_load(Advance::Cursor).await;
+        service.set_candidates(&candidates("abcdef"), false, 2).await;
+
+        assert_eq!(page(&signals), ("def".to_owned(), 0));
+        assert_eq!(service.highlighted().await.unwrap().word, "d");
+    }
+
+    #[tokio::test]
+    async fn answers_to_older_queries_are_ignored() {
+        let (service, signals) = service(false);
+        service.set_candidates(&candidates("xyz"), true, 2).await;
+        service.set_candidates(&candidates("abc"), true, 1).await;
+
+        assert_eq!(page(&signals), ("xyz".to_owned(), 0));
+    }
+
+    #[tokio::test]
+    async fn stale_candidates_cannot_be_selected() {
+        let (service, signals) = service(false);
+        service.set_candidates(&candidates("abc"), true, 1).await;
+        service.rewind(2).await;
+
+        assert!(service.select(1).await.is_none());
+        service.set_candidates(&candidates("abc"), true, 1).await;
+        assert!(service.select(1).await.is_none());
+        service.set_candidates(&candidates("xyz"), true, 2).await;
+        assert_eq!(service.select(2).await.unwrap().word, "y");
+        assert_eq!(signals.committed(), ["y"]);
+    }
+
+    #[tokio::test]
+    async fn offline_notice_is_auxiliary_text() {
+        let (service, signals) = service(false);
+        service.set_candidates(&candidates("abc"), true, 1).await;
+        service.set_offline(2).await;
+
+        assert_eq!(page(&signals), (String::new(), 0));
+        assert_eq!(signals.auxiliary_text(), "（离线）");
+        assert!(service.select(1).await.is_none());
+
+        service.set_candidates(&candidates("xyz"), true, 3).await;
+        assert_eq!(page(&signals), ("xyz".to_owned(), 0));
+        assert_eq!(signals.auxiliary_text(), "");
+    }
 }
@@ -1,101 +1,411 @@
-use regex::Regex;
-use reqwest::header::USER_AGENT;
+use std::time::Duration;
 
-use super::candidate::Candidate;
+use async_trait::async_trait;
+use serde::{de::IgnoredAny, Deserialize};
+
+use super::{
+    candidate::Candidate,
+    candidate_backend::{CandidateBackend, QueryError},
+    config::CloudConfig,
+    input_tool::InputTool,
+};
 
 pub struct CloudPinyinClient {
     http: reqwest::Client,
-    re: Regex,
+    endpoint: String,
+    itc: String,
 }
 
 impl CloudPinyinClient {
-    pub fn new() -> CloudPinyinClient {
+    pub fn new(config: &CloudConfig, input_tool: InputTool) -> CloudPinyinClient {
+        let mut builder = reqwest::Client::builder().user_agent(config.user_agent.as_str());
+
+        if config.timeout_ms > 0 {
+            builder = builder.timeout(Duration::from_millis(config.timeout_ms));
+        }
+
+        if let Some(proxy) = &config.proxy {
+            match reqwest::Proxy::all(proxy.as_str()) {
+                Ok(proxy) => builder = builder.proxy(proxy),
+                Err(e) => println!("Ignoring invalid proxy {}: {}", proxy, e),
+            }
+        }
+
         CloudPinyinClient {
-            http: reqwest::Client::new(),
-            re: Regex::new("[^\"\\[\\],\\{\\}]+").expect("Invalid regex input."),
+            http: builder.build().expect("Failed to build the HTTP client."),
+            endpoint: config.endpoint.clone(),
+            itc: input_tool.code().to_owned(),
         }
     }
-    
-    pub async fn query_candidates(&self, preedit: &str, depth: usize) -> Vec<Candidate> {
-        if preedit.len() == 0 {
-            return Vec::new();
-        }
-        let json = self.get_candidates_from_net(preedit, depth as i32).await;
-        let candidates = self.json_to_candidates(json);
-        candidates
-    }
-
-    async fn get_candidates_from_net(&self, preedit: &str, depth: i32) -> String {
-        let url = format!("https://inputtools.google.com/request?text={}&itc=zh-t-i0-pinyin&num={}&cp=0&cs=1&ie=utf-8&oe=utf-8&app=demopage", preedit, depth);
-
-        let resp = self
-            .http
-            .get(url)
-            .header(
-                USER_AGENT,
-                "Mozilla/5.0 (X11; Linux x86_64; rv:106.0) Gecko/20100101 Firefox/106.0",
-            )
-            .send()
-            .await
-            .expect("Network problems when making the request.");
-
-        resp.text()
-            .await
-            .expect("Network problem when getting the text from the response.")
-    }
-
-    fn json_to_candidates(&self, s: String) -> Vec<Candidate> {
-        let mut linear_data: Vec<String> = Vec::new();
-
-        for caps in self.re.captures_iter(&s) {
-            for cap in caps.iter() {
-                if cap.is_some() {
-                    linear_data.push(cap.unwrap().as_str().to_owned());
-                }
-            }
+
+    // The preedit may contain apostrophes, ü or arbitrary text, so the query string is built by
+    // reqwest, which percent-encodes every value.
+    fn build_request(&self, preedit: &str, depth: i32) -> Result<reqwest::Request, QueryError> {
+        self.http
+            .get(self.endpoint.as_str())
+            .query(&[
+                ("text", preedit),
+                ("itc", self.itc.as_str()),
+                ("num", depth.to_string().as_str()),
+                ("cp", "0"),
+                ("cs", "1"),
+                ("ie", "utf-8"),
+                ("oe", "utf-8"),
+                ("app", "demopage"),
+            ])
+            .build()
+            .map_err(|e| QueryError::InvalidRequest(e.to_string()))
+    }
+
+    async fn get_candidates_from_net(
+        &self,
+        preedit: &str,
+        depth: i32,
+    ) -> Result<String, QueryError> {
+        let request = self.build_request(preedit, depth)?;
+
+        let resp = self.http.execute(request).await.map_err(classify_error)?;
+
+        if !resp.status().is_success() {
+            return Err(QueryError::HttpStatus(resp.status().as_u16()));
         }
 
-        let mut colon_pos: Vec<usize> = Vec::new();
+        resp.text().await.map_err(classify_error)
+    }
+}
 
-        if linear_data[0] != "SUCCESS" {
-            println!("Rust: Google returned irregular data:\n{}", s.as_str());
-            return Vec::new();
+#[async_trait]
+impl CandidateBackend for CloudPinyinClient {
+    async fn query_candidates(
+        &self,
+        preedit: &str,
+        depth: usize,
+    ) -> Result<Vec<Candidate>, QueryError> {
+        if preedit.is_empty() {
+            return Ok(Vec::new());
         }
+        let json = self.get_candidates_from_net(preedit, depth as i32).await?;
+        parse_response(&json)
+    }
+
+    fn input_tool(&self) -> &str {
+        &self.itc
+    }
+}
+
+/*
+The response of Google Input Tools looks like:
 
-        for i in 0..linear_data.len() {
-            if linear_data[i] == ":" {
-                colon_pos.push(i);
+[
+    "SUCCESS",
+    [
+        [
+            "nihao", <- input
+            ["你好", "拟好", "你"], <- candidates
+            [],
+            {
+                "annotation": ["ni hao", "ni hao", "ni"],
+                "candidate_type": [0, 0, 0],
+                "lc": ["16 16", "16 16", "16"],
+                "matched_length": [5, 5, 2] <- may be absent
             }
-        }
+        ]
+    ]
+]
+ */
 
-        let has_matched_len = colon_pos.len() == 4;
+#[derive(Deserialize)]
+struct Response(String, #[serde(default)] Vec<Entry>);
 
-        let candidates = &linear_data[2..colon_pos[0] - 1];
-        let annotations = &linear_data[colon_pos[0] + 1..colon_pos[1] - 1];
+#[derive(Deserialize)]
+struct Entry(IgnoredAny, Vec<String>, IgnoredAny, #[serde(default)] Details);
 
-        let matched_len: Option<&[String]>;
-        if has_matched_len {
-            matched_len = Some(&linear_data[colon_pos[3] + 1..]);
-        } else {
-            matched_len = None;
-        }
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Details {
+    annotation: Vec<String>,
+    matched_length: Option<Vec<i32>>,
+}
+
+fn parse_response(body: &str) -> Result<Vec<Candidate>, QueryError> {
+    let response: Response = serde_json::from_str(body)
+        .map_err(|e| QueryError::MalformedBody(format!("{}: {}", e, body)))?;
+
+    if response.0 != "SUCCESS" {
+        return Err(QueryError::MalformedBody(format!("status {}", response.0)));
+    }
 
-        let mut aggregate: Vec<Candidate> = Vec::new();
-        for i in 0..candidates.len() {
-            aggregate.push(Candidate {
-                word: candidates[i].to_owned(),
-                annotation: annotations[i].to_owned(),
-                matched_len: match matched_len {
-                    Some(len) => Some(
-                        len[i]
-                            .parse::<i32>()
-                            .expect("Matched length faield to be parsed to i32."),
-                    ),
-                    _ => None,
-                },
-            })
+    let entry = match response.1.into_iter().next() {
+        Some(entry) => entry,
+        None => return Ok(Vec::new()),
+    };
+    let words = entry.1;
+    let details = entry.3;
+
+    let mut aggregate: Vec<Candidate> = Vec::new();
+    for (i, word) in words.into_iter().enumerate() {
+        aggregate.push(Candidate {
+            word,
+            annotation: details.annotation.get(i).cloned().unwrap_or_default(),
+            matched_len: details
+                .matched_length
+                .as_ref()
+                .and_then(|len| len.get(i).copied()),
+        })
+    }
+
+    Ok(aggregate)
+}
+
+fn classify_error(e: reqwest::Error) -> QueryError {
+    if e.is_timeout() {
+        return QueryError::Timeout;
+    }
+    if let Some(status) = e.status() {
+        return QueryError::HttpStatus(status.as_u16());
+    }
+    if e.is_decode() || e.is_body() {
+        return QueryError::MalformedBody(e.to_string());
+    }
+
+    // reqwest does not expose resolver failures directly, but hyper names them in the error chain.
+    let mut source = std::error::Error::source(&e);
+    while let Some(inner) = source {
+        if inner.to_string().contains("dns error") {
+            return QueryError::Dns(inner.to_string());
         }
+        source = inner.source();
+    }
+
+    QueryError::Connection(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_url(preedit: &str) -> String {
+        let client = CloudPinyinClient::new(&CloudConfig::default(), InputTool::PinyinSimplified);
+        client.build_request(preedit, 11).unwrap().url().to_string()
+    }
+
+    #[test]
+    fn builds_plain_request() {
+        assert_eq!(
+            request_url("nihao"),
+            "https://inputtools.google.com/request?text=nihao&itc=zh-t-i0-pinyin&num=11&cp=0&cs=1&ie=utf-8&oe=utf-8&app=demopage"
+        );
+    }
+
+    #[test]
+    fn sends_the_input_tool_code() {
+        let client = CloudPinyinClient::new(&CloudConfig::default(), InputTool::Jyutping);
+        let url = client.build_request("nei", 11).unwrap().url().to_string();
+
+        assert!(url.contains("&itc=yue-hant-t-i0-und&"));
+    }
+
+    #[test]
+    fn encodes_apostrophe() {
+        assert_eq!(
+            request_url("xi'an"),
+            "https://inputtools.google.com/request?text=xi%27an&itc=zh-t-i0-pinyin&num=11&cp=0&cs=1&ie=utf-8&oe=utf-8&app=demopage"
+        );
+    }
+
+    #[test]
+    fn encodes_non_ascii() {
+        assert_eq!(
+            request_url("lüe"),
+            "https://inputtools.google.com/request?text=l%C3%BCe&itc=zh-t-i0-pinyin&num=11&cp=0&cs=1&ie=utf-8&oe=utf-8&app=demopage"
+        );
+        assert_eq!(
+            request_url("中文abc"),
+            "https://inputtools.google.com/request?text=%E4%B8%AD%E6%96%87abc&itc=zh-t-i0-pinyin&num=11&cp=0&cs=1&ie=utf-8&oe=utf-8&app=demopage"
+        );
+    }
+
+    #[test]
+    fn preedit_cannot_inject_parameters() {
+        assert_eq!(
+            request_url("ni hao&itc=ja#x"),
+            "https://inputtools.google.com/request?text=ni+hao%26itc%3Dja%23x&itc=zh-t-i0-pinyin&num=11&cp=0&cs=1&ie=utf-8&oe=utf-8&app=demopage"
+        );
+    }
+
+    #[test]
+    fn invalid_endpoint_is_an_error() {
+        let config = CloudConfig {
+            endpoint: "not a url".to_owned(),
+            ..CloudConfig::default()
+        };
+        let client = CloudPinyinClient::new(&config, InputTool::PinyinSimplified);
 
-        aggregate
+        assert!(matches!(
+            client.build_request("nihao", 11),
+            Err(QueryError::InvalidRequest(_))
+        ));
     }
-}
\ No newline at end of file
+
+    fn words(candidates: &[Candidate]) -> Vec<&str> {
+        candidates.iter().map(|c| c.word.as_str()).collect()
+    }
+
+    // These fixtures are hand-written, see tests/fixtures/README.md.
+
+    #[test]
+    fn parses_candidates_with_matched_length() {
+        let body = include_str!("../tests/fixtures/synthetic/nihao.json");
+        let candidates = parse_response(body).unwrap();
+
+        assert_eq!(words(&candidates), ["你好", "拟好", "你", "尼", "泥"]);
+        assert_eq!(candidates[0].annotation, "ni hao");
+        assert_eq!(candidates[0].matched_len, Some(5));
+        assert_eq!(candidates[2].annotation, "ni");
+        assert_eq!(candidates[2].matched_len, Some(2));
+    }
+
+    // Real responses, saved by tests/fixtures/capture.sh.
+    fn captured(text: &str) -> Vec<Candidate> {
+        let path = format!("{}/tests/fixtures/captured/{}.json", env!("CARGO_MANIFEST_DIR"), text);
+        let body = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Run tests/fixtures/capture.sh first, {}: {}", path, e));
+        parse_response(&body).unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs tests/fixtures/captured/, see tests/fixtures/README.md"]
+    fn parses_captured_nihao() {
+        let candidates = captured("nihao");
+
+        assert_eq!(candidates[0].word, "你好");
+        assert_eq!(candidates[0].annotation, "ni hao");
+    }
+
+    #[test]
+    #[ignore = "needs tests/fixtures/captured/, see tests/fixtures/README.md"]
+    fn parses_captured_shijie() {
+        let candidates = captured("shijie");
+
+        assert_eq!(candidates[0].word, "世界");
+        assert_eq!(candidates[0].annotation, "shi jie");
+    }
+
+    #[test]
+    #[ignore = "needs tests/fixtures/captured/, see tests/fixtures/README.md"]
+    fn parses_captured_matched_length() {
+        let candidates = captured("nihaoshijie");
+
+        assert!(candidates
+            .iter()
+            .any(|c| matches!(c.matched_len, Some(n) if n > 0 && n < 11)));
+    }
+
+    #[test]
+    fn missing_matched_length_gives_none() {
+        let body = include_str!("../tests/fixtures/synthetic/shijie_no_matched_length.json");
+        let candidates = parse_response(body).unwrap();
+
+        assert_eq!(words(&candidates), ["世界", "视界", "事解"]);
+        assert!(candidates.iter().all(|c| c.matched_len.is_none()));
+    }
+
+    #[test]
+    fn keeps_punctuation_inside_candidates() {
+        let body = include_str!("../tests/fixtures/synthetic/punctuation_in_candidates.json");
+        let candidates = parse_response(body).unwrap();
+
+        assert_eq!(words(&candidates), ["哈哈", "[哈哈]", "哈,哈", "{哈}", "\"哈\""]);
+        assert_eq!(candidates[2].matched_len, Some(4));
+    }
+
+    #[test]
+    fn failed_status_is_an_error() {
+        let result = parse_response(include_str!("../tests/fixtures/synthetic/failed.json"));
+
+        assert!(matches!(result, Err(QueryError::MalformedBody(_))));
+    }
+
+    #[test]
+    fn truncated_body_is_an_error() {
+        let result = parse_response(include_str!("../tests/fixtures/synthetic/truncated.json"));
+
+        assert!(matches!(result, Err(QueryError::MalformedBody(_))));
+    }
+
+    #[test]
+    fn unexpected_shape_is_an_error() {
+        assert!(parse_response("{}").is_err());
+        assert!(parse_response("[]").is_err());
+        assert!(parse_response("[\"SUCCESS\",[[\"nihao\"]]]").is_err());
+    }
+
+    // Answers the first request on a local port with `response`, then closes the connection, or
+    // keeps it open if `hold` is set.
+    async fn serve_once(response: &'static str, hold: bool) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/request", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 4096];
+            let _ = socket.read(&mut request).await;
+            let _ = socket.write_all(response.as_bytes()).await;
+            if hold {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+        });
+        endpoint
+    }
+
+    async fn query(endpoint: &str) -> Result<Vec<Candidate>, QueryError> {
+        let config = CloudConfig {
+            endpoint: endpoint.to_owned(),
+            timeout_ms: 200,
+            ..CloudConfig::default()
+        };
+        let client = CloudPinyinClient::new(&config, InputTool::PinyinSimplified);
+        client.query_candidates("nihao", 11).await
+    }
+
+    #[tokio::test]
+    async fn classifies_a_timeout() {
+        let endpoint = serve_once("", true).await;
+
+        assert_eq!(query(&endpoint).await.unwrap_err(), QueryError::Timeout);
+    }
+
+    #[tokio::test]
+    async fn classifies_an_http_status() {
+        let response = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n";
+        let endpoint = serve_once(response, true).await;
+
+        assert_eq!(query(&endpoint).await.unwrap_err(), QueryError::HttpStatus(503));
+    }
+
+    #[tokio::test]
+    async fn classifies_a_cut_off_body() {
+        let response = "HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n[\"SUCCESS\"";
+        let endpoint = serve_once(response, false).await;
+
+        assert!(matches!(query(&endpoint).await, Err(QueryError::MalformedBody(_))));
+    }
+
+    #[tokio::test]
+    async fn classifies_a_refused_connection() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/request", listener.local_addr().unwrap());
+        drop(listener);
+
+        assert!(matches!(query(&endpoint).await, Err(QueryError::Connection(_))));
+    }
+
+    // .invalid never resolves (RFC 2606).
+    #[tokio::test]
+    async fn classifies_a_dns_failure() {
+        let result = query("http://inputtools.invalid/request").await;
+
+        assert!(matches!(result, Err(QueryError::Dns(_))), "{:?}", result);
+    }
+}
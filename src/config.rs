@@ -0,0 +1,379 @@
+use std::{path::PathBuf, str::FromStr};
+
+use serde::Deserialize;
+
+use crate::mode_switcher::Hotkey;
+
+// Settings read from $XDG_CONFIG_HOME/ibus-cloud-pinyin/config.toml. Every field has a default, so
+// a missing file or a missing key just means "use the default".
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Cloud,
+    Local,
+    Fixed,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cloud" => Ok(BackendKind::Cloud),
+            "local" => Ok(BackendKind::Local),
+            "fixed" => Ok(BackendKind::Fixed),
+            _ => Err(format!(
+                "Unknown backend {}, expected cloud, local or fixed.",
+                s
+            )),
+        }
+    }
+}
+
+// What happens to an unfinished composition when the input context loses focus or is disabled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FocusLoss {
+    #[default]
+    CommitRaw,
+    CommitFirst,
+    Discard,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub backend: BackendKind,
+    // How long to wait for more keystrokes before asking the backend. Cache hits skip the wait.
+    pub debounce_ms: u64,
+    pub focus_loss: FocusLoss,
+    pub cloud: CloudConfig,
+    pub cache: CacheConfig,
+    pub dictionary: DictionaryConfig,
+    pub conversion: ConversionConfig,
+    pub lookup_table: LookupTableConfig,
+    pub preedit: PreeditConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            backend: BackendKind::default(),
+            debounce_ms: 30,
+            focus_loss: FocusLoss::default(),
+            cloud: CloudConfig::default(),
+            cache: CacheConfig::default(),
+            dictionary: DictionaryConfig::default(),
+            conversion: ConversionConfig::default(),
+            lookup_table: LookupTableConfig::default(),
+            preedit: PreeditConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CloudConfig {
+    pub endpoint: String,
+    pub user_agent: String,
+    // 0 disables the timeout.
+    pub timeout_ms: u64,
+    // e.g. http://proxy:3128 or socks5://127.0.0.1:1080. Without it the usual HTTP_PROXY,
+    // HTTPS_PROXY and ALL_PROXY variables are honoured.
+    pub proxy: Option<String>,
+}
+
+impl Default for CloudConfig {
+    fn default() -> Self {
+        CloudConfig {
+            endpoint: "https://inputtools.google.com/request".to_owned(),
+            user_agent: "Mozilla/5.0 (X11; Linux x86_64; rv:106.0) Gecko/20100101 Firefox/106.0"
+                .to_owned(),
+            timeout_ms: 3000,
+            proxy: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub ttl_secs: u64,
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: true,
+            ttl_secs: 30 * 24 * 60 * 60,
+            max_entries: 50_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DictionaryConfig {
+    pub enabled: bool,
+    // Extra entries on top of the bundled lexicon. Defaults to lexicon.txt next to config.toml.
+    pub path: Option<PathBuf>,
+}
+
+impl Default for DictionaryConfig {
+    fn default() -> Self {
+        DictionaryConfig {
+            enabled: true,
+            path: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConversionConfig {
+    // Start engines in traditional output. toggle_key or the panel toggles it at runtime.
+    pub traditional: bool,
+    // Where STCharacters.txt and STPhrases.txt live. Defaults to opencc/ next to config.toml.
+    pub table_dir: Option<PathBuf>,
+    // Shortcut toggling simplified/traditional output, e.g. "ctrl+shift+f". Empty turns it off.
+    pub toggle_key: String,
+}
+
+impl Default for ConversionConfig {
+    fn default() -> Self {
+        ConversionConfig {
+            traditional: false,
+            table_dir: None,
+            toggle_key: "ctrl+shift+f".to_owned(),
+        }
+    }
+}
+
+impl ConversionConfig {
+    // None if the shortcut is turned off.
+    pub fn toggle_hotkey(&self) -> Option<Hotkey> {
+        Hotkey::parse(&self.toggle_key)
+    }
+
+    fn validate(&mut self) {
+        if !self.toggle_key.is_empty() && self.toggle_hotkey().is_none() {
+            println!(
+                "toggle_key {:?} isn't a shortcut like \"ctrl+shift+f\", turning it off.",
+                self.toggle_key
+            );
+            self.toggle_key.clear();
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Orientation {
+    Horizontal,
+    Vertical,
+    // Whatever the panel is set to.
+    #[default]
+    System,
+}
+
+impl Orientation {
+    // IBusOrientation
+    pub fn to_ibus(self) -> i32 {
+        match self {
+            Orientation::Horizontal => 0,
+            Orientation::Vertical => 1,
+            Orientation::System => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LookupTableConfig {
+    // Candidates per page, 1 to 10.
+    pub page_size: usize,
+    // One label per slot, also the keys that select them, e.g. "1234567890" or "1357924680".
+    pub labels: String,
+    pub orientation: Orientation,
+    // Paging or moving past the last candidate goes back to the first, and vice versa.
+    pub round: bool,
+    // Show each candidate's pinyin next to it, not just the highlighted one's.
+    pub annotations: bool,
+}
+
+impl Default for LookupTableConfig {
+    fn default() -> Self {
+        LookupTableConfig {
+            page_size: 5,
+            labels: "1234567890".to_owned(),
+            orientation: Orientation::default(),
+            round: false,
+            annotations: false,
+        }
+    }
+}
+
+impl LookupTableConfig {
+    fn validate(&mut self) {
+        if !(1..=10).contains(&self.page_size) {
+            println!("page_size must be between 1 and 10, got {}.", self.page_size);
+            self.page_size = self.page_size.clamp(1, 10);
+        }
+        if self.labels.chars().count() < self.page_size {
+            println!(
+                "labels {:?} can't label a page of {}, using digits instead.",
+                self.labels, self.page_size
+            );
+            self.labels = LookupTableConfig::default().labels;
+        }
+        // Only digit and letter keys are looked up as labels; letters select with Alt held.
+        if !self.labels.chars().all(|c| c.is_ascii_digit() || c.is_ascii_lowercase()) {
+            println!(
+                "labels {:?} must be digits or lowercase letters, using digits instead.",
+                self.labels
+            );
+            self.labels = LookupTableConfig::default().labels;
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct PreeditConfig {
+    // Show the highlighted candidate in place of the pinyin it covers. Enter still commits the
+    // letters as typed.
+    pub inline_candidate: bool,
+}
+
+impl Config {
+    pub fn load() -> Config {
+        let mut config = Config::load_file();
+        config.apply_env();
+        config.conversion.validate();
+        config.lookup_table.validate();
+        config
+    }
+
+    fn load_file() -> Config {
+        let path = match config_dir() {
+            Some(dir) => dir.join("config.toml"),
+            None => return Config::default(),
+        };
+
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(_) => return Config::default(),
+        };
+
+        match toml::from_str(&text) {
+            Ok(config) => config,
+            Err(e) => {
+                println!(
+                    "Failed to parse {:?}, falling back to defaults: {}",
+                    path, e
+                );
+                Config::default()
+            }
+        }
+    }
+
+    // Environment variables win over the file, which is handy for one-off runs and for
+    // environments where the proxy is only known at login.
+    fn apply_env(&mut self) {
+        if let Ok(endpoint) = std::env::var("IBUS_CLOUD_PINYIN_ENDPOINT") {
+            self.cloud.endpoint = endpoint;
+        }
+        if let Ok(user_agent) = std::env::var("IBUS_CLOUD_PINYIN_USER_AGENT") {
+            self.cloud.user_agent = user_agent;
+        }
+        if let Ok(timeout) = std::env::var("IBUS_CLOUD_PINYIN_TIMEOUT_MS") {
+            match timeout.parse() {
+                Ok(timeout) => self.cloud.timeout_ms = timeout,
+                Err(_) => println!("Ignoring invalid IBUS_CLOUD_PINYIN_TIMEOUT_MS={}", timeout),
+            }
+        }
+        if let Ok(proxy) = std::env::var("IBUS_CLOUD_PINYIN_PROXY") {
+            self.cloud.proxy = Some(proxy);
+        }
+    }
+}
+
+pub fn config_dir() -> Option<PathBuf> {
+    let config_home = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => home::home_dir()?.join(".config"),
+    };
+    Some(config_home.join("ibus-cloud-pinyin"))
+}
+
+pub fn cache_dir() -> Option<PathBuf> {
+    let cache_home = match std::env::var("XDG_CACHE_HOME") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => home::home_dir()?.join(".cache"),
+    };
+    Some(cache_home.join("ibus-cloud-pinyin"))
+}
+
+pub fn data_dir() -> Option<PathBuf> {
+    let data_home = match std::env::var("XDG_DATA_HOME") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => home::home_dir()?.join(".local/share"),
+    };
+    Some(data_home.join("ibus-cloud-pinyin"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validated(page_size: usize, labels: &str) -> LookupTableConfig {
+        let mut config = LookupTableConfig {
+            page_size,
+            labels: labels.to_owned(),
+            ..LookupTableConfig::default()
+        };
+        config.validate();
+        config
+    }
+
+    #[test]
+    fn keeps_valid_labels() {
+        assert_eq!(validated(5, "13579").labels, "13579");
+    }
+
+    #[test]
+    fn keeps_letter_labels() {
+        assert_eq!(validated(5, "asdfghjkl").labels, "asdfghjkl");
+    }
+
+    #[test]
+    fn rejects_labels_that_are_not_keys_we_look_up() {
+        assert_eq!(validated(5, ",./;'").labels, "1234567890");
+        assert_eq!(validated(5, "ASDFG").labels, "1234567890");
+    }
+
+    #[test]
+    fn turns_off_an_invalid_toggle_key() {
+        let mut config = ConversionConfig {
+            toggle_key: "hyper+f".to_owned(),
+            ..ConversionConfig::default()
+        };
+        config.validate();
+        assert_eq!(config.toggle_hotkey(), None);
+
+        let mut config = ConversionConfig::default();
+        config.validate();
+        assert_eq!(config.toggle_hotkey(), Hotkey::parse("ctrl+shift+f"));
+    }
+
+    #[test]
+    fn rejects_too_few_labels() {
+        let config = validated(12, "123");
+        assert_eq!(config.page_size, 10);
+        assert_eq!(config.labels, "1234567890");
+    }
+}
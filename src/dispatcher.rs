@@ -1,14 +1,16 @@
-use std::{default, sync::Arc};
+use std::{sync::Arc, time::Duration};
 
 use zbus::Connection;
 
-use super::ibus_proxy::IBusProxy;
-use crate::{candidate::Candidate, keys::Key, mode_switcher::{Flags, KeyContent}, preedit_service::PreeditService};
-use tokio::sync::Mutex;
+use super::ibus_proxy::{EngineSignals, IBusProxy};
+use crate::{candidate::Candidate, config::{Config, FocusLoss}, keys::Key, mode_switcher::KeyContent, preedit_service::PreeditService};
+use tokio::sync::{watch, Mutex};
 
 use super::{
-    candidate_service::CandidateService, cloud_pinyin_client::CloudPinyinClient,
-    number_service::NumberService, symbol_service::SymbolService,
+    candidate_backend::Backends,
+    candidate_service::{Advance, CandidateService},
+    number_service::NumberService, script_converter::ScriptConverter,
+    symbol_service::SymbolService,
 };
 
 pub struct Dispatcher {
@@ -16,27 +18,81 @@ pub struct Dispatcher {
     pub preedit_svc: PreeditService,
     symbol_svc: SymbolService,
     number_svc: NumberService,
-    client: CloudPinyinClient,
-    ibus: Arc<Mutex<IBusProxy>>,
+    backends: Backends,
+    ibus: Arc<Mutex<dyn EngineSignals>>,
     level: Vec<usize>,
+    // Bumped for every query and whenever the session ends. A query that is no longer the latest
+    // is dropped as soon as a newer one starts, which also cancels its HTTP request.
+    generation: watch::Sender<u64>,
+    debounce: Duration,
+    focus_loss: FocusLoss,
 }
 
 impl Dispatcher {
-    pub fn new(conn: &Connection) -> Dispatcher {
-        let ibus: Arc<Mutex<IBusProxy>> = Arc::new(Mutex::new(IBusProxy::new(conn)));
+    pub fn new(
+        conn: &Connection,
+        path: &str,
+        backends: Backends,
+        converter: Option<Arc<ScriptConverter>>,
+        config: &Config,
+    ) -> Dispatcher {
+        let ibus: Arc<Mutex<dyn EngineSignals>> = Arc::new(Mutex::new(IBusProxy::new(conn, path)));
+        Dispatcher::with_ibus(ibus, backends, converter, config)
+    }
+
+    pub fn with_ibus(
+        ibus: Arc<Mutex<dyn EngineSignals>>,
+        backends: Backends,
+        converter: Option<Arc<ScriptConverter>>,
+        config: &Config,
+    ) -> Dispatcher {
         Dispatcher {
-            candidate_svc: CandidateService::new(ibus.clone()),
-            preedit_svc: PreeditService::new(ibus.clone()),
+            candidate_svc: CandidateService::new(
+                ibus.clone(),
+                converter,
+                config.conversion.traditional,
+                config.conversion.toggle_hotkey(),
+                &config.lookup_table,
+            ),
+            preedit_svc: PreeditService::new(
+                ibus.clone(),
+                config.preedit.inline_candidate,
+                backends.input_tool.is_pinyin(),
+            ),
             symbol_svc: SymbolService::new(ibus.clone()),
             number_svc: NumberService::new(ibus.clone()),
-            client: CloudPinyinClient::new(),
+            backends,
             ibus: ibus.clone(),
             level: vec![11, 21, 41, 81, 161, 321, 641, 1281],
+            generation: watch::channel(0).0,
+            debounce: Duration::from_millis(config.debounce_ms),
+            focus_loss: config.focus_loss,
         }
     }
 
-    pub async fn on_input(&self, key_content: KeyContent) -> bool {
+    pub async fn on_input(self: &Arc<Self>, key_content: KeyContent) -> bool {
         
+        // Mid-composition ' separates syllables instead of typing quotes.
+        if key_content.key == Key::SingleQuote && !self.preedit_svc.is_empty().await {
+            if !key_content.flags.is_release && self.preedit_svc.push_boundary().await {
+                self.requery().await;
+            }
+            return true;
+        }
+
+        // Digits and punctuation that spell go into the preedit like letters. Held with Alt, they
+        // are labels, symbols or paging keys as usual.
+        if self.backends.input_tool.spells_with(key_content.key) && !key_content.flags.is_alt {
+            return self.handle_pinyin(key_content).await;
+        }
+
+        // Labels select while composing, even before the first candidates have arrived.
+        if let Some(ith) = self.label_position(&key_content) {
+            if self.in_session().await {
+                return self.handle_select(ith, key_content).await;
+            }
+        }
+
         match key_content.key {
             Key::a
             | Key::b
@@ -75,11 +131,12 @@ impl Dispatcher {
             | Key::_7
             | Key::_8
             | Key::_9 => {
-                if self.candidate_svc.in_session().await {
-                    return self.handle_select(key_content).await;
+                if self.in_session().await {
+                    // Not one of the labels; don't let it into the composition.
+                    true
                 } else {
                     self.number_svc.handle_number(key_content).await;
-                    return true;
+                    true
                 }
             }
 
@@ -96,18 +153,26 @@ impl Dispatcher {
             | Key::ExclamationMark
             | Key::Ellipsis => {
                 self.symbol_svc.handle_symbol(key_content).await;
-                return true;
+                true
             }
 
+            // Only spells in Zhuyin, otherwise typed as is.
+            Key::Slash => false,
+
             Key::Space
             | Key::Enter
             | Key::Minus
             | Key::Equal
+            | Key::PageUp
+            | Key::PageDown
             | Key::Up
             | Key::Down
             | Key::Left
             | Key::Right
+            | Key::Home
+            | Key::End
             | Key::Backspace
+            | Key::Delete
             | Key::Escape => return self.handle_control(key_content).await,
             
             Key::Shift | Key::Ctrl | Key::Alt => panic!("Unexpected control keys received."),
@@ -141,133 +206,725 @@ impl Dispatcher {
         }
     }
 
-    pub async fn handle_pinyin(&self, key_content: KeyContent) -> bool {
+    pub async fn handle_pinyin(self: &Arc<Self>, key_content: KeyContent) -> bool {
         
         if key_content.flags.is_release {
             return true;
         }
 
-        let c = key_content.key.to_char().expect("A-Z cannot be converted to a char.");
+        let c = key_content.key.to_char().expect("This key doesn't spell anything.");
 
         self.preedit_svc.push(c).await;
-        
-        //let preedit = self.preedit_svc.to_string().await;
-        //let candidates = self.client.query_candidates(&preedit, self.level[0]).await;
-
-        // 
-        // DEBUG //////////////////
-        // 
-        let candidates = vec![
-            Candidate {
-                word: "你好".to_string(),
-                annotation: "".to_string(), // 可以留空或添加调试信息
-                matched_len: None,          // 对于固定列表，通常设为 None
-            },
-            Candidate {
-                word: "世界".to_string(),
-                annotation: "".to_string(),
-                matched_len: None,
-            },
-        ];
-
-        //println!("4. Fetched candidates: {:?}", candidates);
-        self.candidate_svc.set_candidates(&candidates).await;
+        self.requery().await;
 
         true
     }
 
-    pub async fn handle_select(&self, key_content: KeyContent) -> bool {
+    pub async fn handle_select(self: &Arc<Self>, ith: usize, key_content: KeyContent) -> bool {
         
         if key_content.flags.is_release {
             return true
         }
 
-        self.preedit_svc.clear().await;
-
-        let i = key_content.key.to_usize().expect("Failed to conver the key to usize.");
-        self.candidate_svc.select(i).await;
-        self.candidate_svc.clear().await;
+        if !self.wait_for_candidates().await {
+            return true;
+        }
+        if let Some(candidate) = self.candidate_svc.select(ith).await {
+            self.finish_selection(&candidate).await;
+        }
 
         true
     }
 
-    pub async fn handle_control(&self, key_content: KeyContent) -> bool {
-        if !self.candidate_svc.in_session().await {
+    pub async fn page_up(&self) {
+        if !self.wait_for_candidates().await {
+            return;
+        }
+        self.candidate_svc.page_back().await;
+        self.sync_converted().await;
+    }
+
+    // Moves to the next page, asking the backend for more candidates if we have run out.
+    pub async fn page_down(self: &Arc<Self>) {
+        if !self.wait_for_candidates().await {
+            return;
+        }
+        let (enough, min_needed) = self.candidate_svc.page_into().await;
+        if !enough {
+            let min = min_needed
+                .expect("Not enough to fill lookup table but min_needed is None.");
+            self.load_more(min, Advance::Page).await;
+        }
+        self.sync_converted().await;
+    }
+
+    pub async fn cursor_up(&self) {
+        if !self.wait_for_candidates().await {
+            return;
+        }
+        self.candidate_svc.cursor_up().await;
+        self.sync_converted().await;
+    }
+
+    // Like page_down, but one candidate at a time.
+    pub async fn cursor_down(self: &Arc<Self>) {
+        if !self.wait_for_candidates().await {
+            return;
+        }
+        let (enough, min_needed) = self.candidate_svc.cursor_down().await;
+        if !enough {
+            let min = min_needed
+                .expect("Not enough candidates to move the cursor but min_needed is None.");
+            self.load_more(min, Advance::Cursor).await;
+        }
+        self.sync_converted().await;
+    }
+
+    pub async fn toggle_traditional(&self) {
+        self.candidate_svc.toggle_traditional().await;
+        self.sync_converted().await;
+    }
+
+    pub async fn property_activate(&self, name: &str) {
+        self.candidate_svc.property_activate(name).await;
+        self.sync_converted().await;
+    }
+
+    // Highlights the part of the preedit the highlighted candidate stands for.
+    async fn sync_converted(&self) {
+        let (converted, word, annotation) = match self.candidate_svc.highlighted().await {
+            Some(candidate) => {
+                let converted = match candidate.matched_len {
+                    Some(matched) if matched > 0 => matched as usize,
+                    _ => self.preedit_svc.len().await,
+                };
+                (converted, candidate.word, candidate.annotation)
+            }
+            None => (0, String::new(), String::new()),
+        };
+        self.preedit_svc.set_converted(converted, &word, &annotation).await;
+    }
+
+    // The page or highlight move that ran out of candidates is made once they have been loaded.
+    async fn load_more(self: &Arc<Self>, min: usize, advance: Advance) {
+        let mut to_load = 0;
+        for qty in &self.level {
+            if qty >= &min {
+                to_load = *qty;
+                break;
+            }
+        }
+        // Past the last level there is nothing more to ask for.
+        if to_load == 0 {
+            return;
+        }
+
+        self.candidate_svc.advance_on_load(advance).await;
+        let preedit = self.preedit_svc.to_string().await;
+        // The candidates shown stay valid; the longer list just replaces them once it arrives.
+        let generation = self.next_generation();
+        self.spawn_refresh(preedit, to_load, generation);
+    }
+
+    // The preedit changed: start over from the top of a fresh candidate list. Deleting the last
+    // letter ends the session.
+    async fn requery(self: &Arc<Self>) {
+        if self.preedit_svc.is_empty().await {
+            self.reset().await;
+            return;
+        }
+        let generation = self.retire_candidates().await;
+        let preedit = self.preedit_svc.to_string().await;
+        self.spawn_refresh(preedit, self.level[0], generation);
+    }
+
+    // Digit labels select on their own. Letters go to the preedit, so letter labels select with
+    // Alt held.
+    fn label_position(&self, key_content: &KeyContent) -> Option<usize> {
+        let label = key_content.key.to_char()?;
+        let selects =
+            label.is_ascii_digit() || (label.is_ascii_lowercase() && key_content.flags.is_alt);
+        if !selects {
+            return None;
+        }
+        self.candidate_svc.label_position(label)
+    }
+
+    // index is 0-based within the current page, as sent with CandidateClicked.
+    pub async fn handle_click(self: &Arc<Self>, index: u32) {
+        if !self.wait_for_candidates().await {
+            return;
+        }
+        if let Some(candidate) = self.candidate_svc.select(index as usize + 1).await {
+            self.finish_selection(&candidate).await;
+        }
+    }
+
+    // A candidate covering only the start of the preedit (e.g. 你 for nihao) leaves the rest of the
+    // pinyin in the preedit and asks for candidates for it, so sentences can be built piecewise.
+    // matched_len counts typed chars, ' included, as that is what the backend was sent.
+    async fn finish_selection(self: &Arc<Self>, candidate: &Candidate) {
+        let preedit_len = self.preedit_svc.len().await;
+        let matched = match candidate.matched_len {
+            Some(matched) if matched > 0 => matched as usize,
+            _ => preedit_len,
+        };
+
+        if matched < preedit_len {
+            self.preedit_svc.consume(matched).await;
+            self.requery().await;
+        } else {
+            self.reset().await;
+        }
+    }
+
+    pub async fn handle_control(self: &Arc<Self>, key_content: KeyContent) -> bool {
+        if !self.in_session().await {
             return false;
         }
 
+        // Presses do the work; swallow the matching releases so they aren't acted on twice.
+        if key_content.flags.is_release {
+            return true;
+        }
+
         match key_content.key {
-            Key::Space => return self.handle_select(KeyContent { 
-                key: Key::_1, 
-                flags: Flags {
-                    is_ignored : true,
-                    ..Default::default()
-                }, 
-                key_code: 0 ,
-            }).await,
+            Key::Space => {
+                if !self.wait_for_candidates().await {
+                    return true;
+                }
+                if let Some(candidate) = self.candidate_svc.select_highlighted().await {
+                    self.finish_selection(&candidate).await;
+                }
 
+                true
+            }
             Key::Enter => {
+                // Enter commits the letters as typed unless the user picked a candidate with the
+                // arrow keys.
+                if self.candidate_svc.navigated().await {
+                    if let Some(candidate) = self.candidate_svc.select_highlighted().await {
+                        self.finish_selection(&candidate).await;
+                        return true;
+                    }
+                }
+
                 let preedit = self.preedit_svc.to_string().await;
-                self.preedit_svc.clear().await;
-                self.candidate_svc.clear().await;
+                self.reset().await;
                 self.ibus.lock().await.commit_text(&preedit).await;
 
-                return true;
+                true
             }
-            Key::Minus => {
-                self.candidate_svc.page_back().await;
+            Key::Minus | Key::PageUp => {
+                self.page_up().await;
 
-                return true;
+                true
             }
-            Key::Equal => {
-                let (enough, min_needed) = self.candidate_svc.page_into().await;
-                if !enough {
-                    let min = min_needed
-                        .expect("Not enough to fill lookup table but min_needed is None.");
-
-                    let mut to_load = 0;
-                    for qty in &self.level {
-                        if qty >= &min {
-                            to_load = *qty;
-                            break;
-                        }
-                    }
+            Key::Equal | Key::PageDown => {
+                self.page_down().await;
 
-                    let candidates = self
-                        .client
-                        .query_candidates(&self.preedit_svc.to_string().await, to_load)
-                        .await;
-                    self.candidate_svc.set_candidates(&candidates).await;
-                }
+                true
+            }
+            Key::Up => {
+                self.cursor_up().await;
 
-                return true;
+                true
             }
-            Key::Up => return false,    // For now, ingore
-            Key::Down => return false,  // For now, ignore
-            Key::Left => return false,  // For now, ignore
-            Key::Right => return false, // For now, ignore
-            Key::Backspace => {
-                let popped = self.preedit_svc.pop().await;
+            Key::Down => {
+                self.cursor_down().await;
 
-                if popped.is_none() {
-                    return false;
-                }
+                true
+            }
+            // Moving around the preedit doesn't change what it spells, so no new query.
+            Key::Left => {
+                self.preedit_svc.move_left().await;
+
+                true
+            }
+            Key::Right => {
+                self.preedit_svc.move_right().await;
 
-                let preedit: String = self.preedit_svc.to_string().await;
+                true
+            }
+            Key::Home => {
+                self.preedit_svc.move_home().await;
 
-                let candidates = self.client.query_candidates(&preedit, self.level[0]).await;
+                true
+            }
+            Key::End => {
+                self.preedit_svc.move_end().await;
 
-                self.candidate_svc.set_candidates(&candidates).await;
+                true
+            }
+            Key::Backspace => {
+                if self.preedit_svc.pop().await.is_some() {
+                    self.requery().await;
+                }
+
+                true
+            }
+            Key::Delete => {
+                if self.preedit_svc.delete().await.is_some() {
+                    self.requery().await;
+                }
 
-                return true;
+                true
             }
             Key::Escape => {
-                self.preedit_svc.clear().await;
-                self.candidate_svc.clear().await;
+                self.reset().await;
 
-                return true;
+                true
             }
             _ => panic!("Invalid control key."),
         }
     }
+
+    // Ends the session: in-flight queries are abandoned, preedit and candidates are cleared.
+    pub async fn reset(&self) {
+        self.retire_candidates().await;
+        self.preedit_svc.clear().await;
+        self.candidate_svc.clear().await;
+    }
+
+    // The input context lost focus or was disabled mid-composition. Whatever the configuration
+    // says to keep is committed; the session ends either way.
+    pub async fn on_focus_out(&self) {
+        if !self.in_session().await {
+            return;
+        }
+
+        let preedit = self.preedit_svc.to_string().await;
+        let text = match self.focus_loss {
+            FocusLoss::CommitRaw => preedit,
+            FocusLoss::CommitFirst => match self.candidate_svc.first().await {
+                Some(candidate) => {
+                    // Pinyin the candidate doesn't cover is kept as typed.
+                    let rest: String = match candidate.matched_len {
+                        Some(matched) if matched > 0 => {
+                            preedit.chars().skip(matched as usize).collect()
+                        }
+                        _ => String::new(),
+                    };
+                    candidate.word + &rest
+                }
+                // No candidates, or only stale ones for an older preedit.
+                None => preedit,
+            },
+            FocusLoss::Discard => String::new(),
+        };
+
+        self.reset().await;
+        if !text.is_empty() {
+            self.ibus.lock().await.commit_text(&text).await;
+        }
+    }
+
+    // Keys are acknowledged before the candidates for them arrive. Anything that picks from or
+    // moves through the list waits for the query in flight first, so it never acts on the list of
+    // an older preedit. Returns false if that query is superseded or abandoned meanwhile, in which
+    // case the key must be dropped: the list it was meant for is gone.
+    async fn wait_for_candidates(&self) -> bool {
+        let generation = *self.generation.borrow();
+        let mut latest = self.generation.subscribe();
+        tokio::select! {
+            _ = self.candidate_svc.wait_until_fresh() => *self.generation.borrow() == generation,
+            _ = latest.wait_for(|g| *g != generation) => false,
+        }
+    }
+
+    // Marks the candidates on screen stale before starting a new generation, so nothing can pick
+    // from them, or replace them with the answer to an older query, in between.
+    async fn retire_candidates(&self) -> u64 {
+        let generation = *self.generation.borrow() + 1;
+        self.candidate_svc.rewind(generation).await;
+        self.next_generation()
+    }
+
+    pub fn cancel_pending(&self) {
+        self.next_generation();
+    }
+
+    fn next_generation(&self) -> u64 {
+        let mut generation = 0;
+        self.generation.send_modify(|g| {
+            *g += 1;
+            generation = *g;
+        });
+        generation
+    }
+
+    // A session lasts while there is a preedit, even if the backend failed and left us without
+    // candidates.
+    async fn in_session(&self) -> bool {
+        self.candidate_svc.in_session().await || !self.preedit_svc.is_empty().await
+    }
+
+    // The key is acknowledged right away and the lookup table is updated once the answer arrives,
+    // so a slow backend never holds up IBus.
+    // The generation is taken before spawning so generations follow keystroke order, not task
+    // scheduling.
+    fn spawn_refresh(self: &Arc<Self>, preedit: String, depth: usize, generation: u64) {
+        let dispatcher = self.clone();
+        tokio::spawn(async move {
+            dispatcher
+                .refresh_candidates(&preedit, depth, generation)
+                .await;
+        });
+    }
+
+    async fn refresh_candidates(&self, preedit: &str, depth: usize, generation: u64) {
+        let mut latest = self.generation.subscribe();
+
+        // Cached answers are shown right away; only real requests wait for typing to settle.
+        let debounced_query = async {
+            match self.backends.cached(preedit, depth) {
+                Some(candidates) => Ok(candidates),
+                None => {
+                    tokio::time::sleep(self.debounce).await;
+                    self.backends.fetch(preedit, depth).await
+                }
+            }
+        };
+
+        let result = tokio::select! {
+            result = debounced_query => result,
+            _ = latest.wait_for(|g| *g != generation) => return,
+        };
+
+        if *self.generation.borrow() != generation {
+            return;
+        }
+
+        match result {
+            Ok(candidates) => {
+                let complete = candidates.len() < depth;
+                self.candidate_svc
+                    .set_candidates(&candidates, complete, generation)
+                    .await;
+                self.sync_converted().await;
+            }
+            Err(e) => {
+                println!("Failed to query candidates for {}: {}", preedit, e);
+                self.candidate_svc.set_offline(generation).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        candidate_backend::{CandidateBackend, QueryError},
+        ibus_proxy::recording::RecordingSignals,
+        input_tool::InputTool,
+        mode_switcher::Flags,
+    };
+
+    // Answers with the preedit in upper case, followed by the partial candidate set for it, after
+    // the delay set for it. Records which queries were started and which ran to the end.
+    #[derive(Default)]
+    struct DelayedBackend {
+        delays_ms: HashMap<&'static str, u64>,
+        partial: HashMap<&'static str, (&'static str, i32)>,
+        started: std::sync::Mutex<Vec<String>>,
+        finished: std::sync::Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl CandidateBackend for DelayedBackend {
+        async fn query_candidates(
+            &self,
+            preedit: &str,
+            _depth: usize,
+        ) -> Result<Vec<Candidate>, QueryError> {
+            self.started.lock().unwrap().push(preedit.to_owned());
+            let delay = self.delays_ms.get(preedit).copied().unwrap_or(0);
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            self.finished.lock().unwrap().push(preedit.to_owned());
+            let mut candidates = vec![Candidate {
+                word: preedit.to_uppercase(),
+                annotation: "".to_owned(),
+                matched_len: None,
+            }];
+            if let Some((word, matched_len)) = self.partial.get(preedit) {
+                candidates.push(Candidate {
+                    word: word.to_string(),
+                    annotation: "".to_owned(),
+                    matched_len: Some(*matched_len),
+                });
+            }
+            Ok(candidates)
+        }
+
+        fn input_tool(&self) -> &str {
+            "delayed"
+        }
+    }
+
+    fn dispatcher(backend: Arc<DelayedBackend>) -> (Arc<Dispatcher>, RecordingSignals) {
+        dispatcher_for(InputTool::PinyinSimplified, backend)
+    }
+
+    fn dispatcher_for(
+        input_tool: InputTool,
+        backend: Arc<DelayedBackend>,
+    ) -> (Arc<Dispatcher>, RecordingSignals) {
+        let config = Config {
+            debounce_ms: 0,
+            ..Config::default()
+        };
+        dispatcher_with(input_tool, &config, backend)
+    }
+
+    fn dispatcher_with(
+        input_tool: InputTool,
+        config: &Config,
+        backend: Arc<DelayedBackend>,
+    ) -> (Arc<Dispatcher>, RecordingSignals) {
+        let signals = RecordingSignals::default();
+        let backends = Backends {
+            input_tool,
+            primary: backend,
+            cache: None,
+            fallback: None,
+        };
+        let dispatcher = Dispatcher::with_ibus(
+            Arc::new(Mutex::new(signals.clone())),
+            backends,
+            None,
+            config,
+        );
+        (Arc::new(dispatcher), signals)
+    }
+
+    fn press(key: Key) -> KeyContent {
+        KeyContent {
+            key,
+            flags: Flags::default(),
+            key_code: 0,
+        }
+    }
+
+    fn press_with_alt(key: Key) -> KeyContent {
+        KeyContent {
+            key,
+            flags: Flags {
+                is_alt: true,
+                ..Flags::default()
+            },
+            key_code: 0,
+        }
+    }
+
+    async fn type_keys(dispatcher: &Arc<Dispatcher>, keys: &[Key]) {
+        for key in keys {
+            dispatcher.on_input(press(*key)).await;
+        }
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    #[tokio::test]
+    async fn label_selects_once_the_candidates_arrive() {
+        let backend = Arc::new(DelayedBackend {
+            delays_ms: HashMap::from([("ni", 30)]),
+            ..DelayedBackend::default()
+        });
+        let (dispatcher, signals) = dispatcher(backend);
+
+        type_keys(&dispatcher, &[Key::n, Key::i, Key::_1]).await;
+
+        assert_eq!(signals.committed(), ["NI"]);
+        assert_eq!(signals.preedit().0, "");
+    }
+
+    #[tokio::test]
+    async fn label_is_dropped_when_its_candidates_are_superseded() {
+        let backend = Arc::new(DelayedBackend {
+            delays_ms: HashMap::from([("ni", 200)]),
+            ..DelayedBackend::default()
+        });
+        let (dispatcher, signals) = dispatcher(backend);
+
+        // The candidates for n are on screen while those for ni are on their way.
+        type_keys(&dispatcher, &[Key::n]).await;
+        settle().await;
+        type_keys(&dispatcher, &[Key::i]).await;
+        let waiting = tokio::spawn({
+            let dispatcher = dispatcher.clone();
+            async move { dispatcher.on_input(press(Key::_1)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        type_keys(&dispatcher, &[Key::n]).await;
+
+        assert!(waiting.await.unwrap());
+        settle().await;
+        assert!(signals.committed().is_empty());
+        assert_eq!(signals.preedit().0, "nin");
+        assert_eq!(signals.page().0, ["NIN"]);
+    }
+
+    #[tokio::test]
+    async fn label_is_dropped_when_the_session_ends() {
+        let backend = Arc::new(DelayedBackend {
+            delays_ms: HashMap::from([("ni", 200)]),
+            ..DelayedBackend::default()
+        });
+        let (dispatcher, signals) = dispatcher(backend);
+
+        type_keys(&dispatcher, &[Key::n]).await;
+        settle().await;
+        type_keys(&dispatcher, &[Key::i]).await;
+        let waiting = tokio::spawn({
+            let dispatcher = dispatcher.clone();
+            async move { dispatcher.on_input(press(Key::_1)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        type_keys(&dispatcher, &[Key::Escape]).await;
+
+        assert!(waiting.await.unwrap());
+        assert!(signals.committed().is_empty());
+        assert!(!dispatcher.in_session().await);
+    }
+
+    #[tokio::test]
+    async fn slow_answer_to_an_older_query_is_dropped() {
+        let backend = Arc::new(DelayedBackend {
+            delays_ms: HashMap::from([("ni", 100)]),
+            ..DelayedBackend::default()
+        });
+        let (dispatcher, signals) = dispatcher(backend.clone());
+
+        type_keys(&dispatcher, &[Key::n, Key::i]).await;
+        // Let the query for ni start before it is superseded.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        type_keys(&dispatcher, &[Key::h, Key::a, Key::o]).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(signals.page().0, ["NIHAO"]);
+        assert!(backend.started.lock().unwrap().contains(&"ni".to_owned()));
+        assert!(!backend.finished.lock().unwrap().contains(&"ni".to_owned()));
+        assert_eq!(backend.finished.lock().unwrap().last().unwrap(), "nihao");
+    }
+
+    #[tokio::test]
+    async fn partial_candidate_leaves_the_rest_of_the_pinyin() {
+        let backend = Arc::new(DelayedBackend {
+            partial: HashMap::from([("ni'hao", ("你", 2))]),
+            ..DelayedBackend::default()
+        });
+        let (dispatcher, signals) = dispatcher(backend);
+
+        type_keys(&dispatcher, &[Key::n, Key::i, Key::SingleQuote, Key::h, Key::a, Key::o]).await;
+        settle().await;
+        assert_eq!(signals.page().0, ["NI'HAO", "你"]);
+
+        // The typed ' after the consumed ni goes with it.
+        type_keys(&dispatcher, &[Key::_2]).await;
+        assert_eq!(signals.committed(), ["你"]);
+        assert_eq!(dispatcher.preedit_svc.to_string().await, "hao");
+
+        settle().await;
+        assert_eq!(signals.page().0, ["HAO"]);
+        type_keys(&dispatcher, &[Key::_1]).await;
+        assert_eq!(signals.committed(), ["你", "HAO"]);
+        assert!(!dispatcher.in_session().await);
+    }
+
+    #[tokio::test]
+    async fn zhuyin_spells_with_digits_and_punctuation() {
+        let backend = Arc::new(DelayedBackend::default());
+        let (dispatcher, signals) = dispatcher_for(InputTool::Zhuyin, backend);
+
+        let keys = [Key::s, Key::u, Key::_3, Key::c, Key::l, Key::_3, Key::Minus];
+        type_keys(&dispatcher, &keys).await;
+        settle().await;
+        assert_eq!(signals.preedit().0, "su3cl3-");
+        assert_eq!(signals.page().0, ["SU3CL3-"]);
+
+        // Held with Alt, the digit is a label again.
+        dispatcher.on_input(press_with_alt(Key::_1)).await;
+        assert_eq!(signals.committed(), ["SU3CL3-"]);
+    }
+
+    #[tokio::test]
+    async fn minus_is_the_long_vowel_in_romaji_and_pages_in_pinyin() {
+        let backend = Arc::new(DelayedBackend::default());
+        let (romaji, signals) = dispatcher_for(InputTool::JapaneseRomaji, backend);
+        type_keys(&romaji, &[Key::r, Key::a, Key::Minus, Key::m, Key::e, Key::n]).await;
+        assert_eq!(signals.preedit().0, "ra-men");
+
+        let backend = Arc::new(DelayedBackend::default());
+        let (pinyin, signals) = dispatcher(backend);
+        type_keys(&pinyin, &[Key::x, Key::i, Key::Minus, Key::a, Key::n]).await;
+        assert_eq!(signals.preedit().0, "xian");
+    }
+
+    #[tokio::test]
+    async fn letter_labels_select_with_alt() {
+        let backend = Arc::new(DelayedBackend {
+            partial: HashMap::from([("nih", ("你", 2))]),
+            ..DelayedBackend::default()
+        });
+        let mut config = Config {
+            debounce_ms: 0,
+            ..Config::default()
+        };
+        config.lookup_table.labels = "asdfghjkl".to_owned();
+        let (dispatcher, signals) = dispatcher_with(InputTool::PinyinSimplified, &config, backend);
+
+        // Plain s spells, Alt+s is the second label.
+        assert_eq!(dispatcher.label_position(&press(Key::s)), None);
+        assert_eq!(dispatcher.label_position(&press_with_alt(Key::s)), Some(2));
+        assert_eq!(dispatcher.label_position(&press_with_alt(Key::_1)), None);
+
+        type_keys(&dispatcher, &[Key::n, Key::i, Key::h]).await;
+        settle().await;
+        dispatcher.on_input(press_with_alt(Key::s)).await;
+        assert_eq!(signals.committed(), ["你"]);
+        assert_eq!(dispatcher.preedit_svc.to_string().await, "h");
+    }
+
+    #[tokio::test]
+    async fn digit_labels_select_without_alt() {
+        let backend = Arc::new(DelayedBackend::default());
+        let (dispatcher, _) = dispatcher(backend);
+
+        assert_eq!(dispatcher.label_position(&press(Key::_3)), Some(3));
+        assert_eq!(dispatcher.label_position(&press_with_alt(Key::_3)), Some(3));
+        assert_eq!(dispatcher.label_position(&press_with_alt(Key::a)), None);
+    }
+
+    #[tokio::test]
+    async fn focus_loss_commits_the_letters_over_stale_candidates() {
+        let backend = Arc::new(DelayedBackend {
+            delays_ms: HashMap::from([("ni", 200)]),
+            ..DelayedBackend::default()
+        });
+        let config = Config {
+            debounce_ms: 0,
+            focus_loss: FocusLoss::CommitFirst,
+            ..Config::default()
+        };
+        let (dispatcher, signals) = dispatcher_with(InputTool::PinyinSimplified, &config, backend);
+
+        type_keys(&dispatcher, &[Key::n]).await;
+        settle().await;
+        dispatcher.on_focus_out().await;
+        assert_eq!(signals.committed(), ["N"]);
+
+        // The candidates for n are still shown while those for ni are on their way.
+        type_keys(&dispatcher, &[Key::n]).await;
+        settle().await;
+        type_keys(&dispatcher, &[Key::i]).await;
+        dispatcher.on_focus_out().await;
+        assert_eq!(signals.committed(), ["N", "ni"]);
+    }
 }
@@ -1,3 +1,5 @@
+#![allow(clippy::all, dead_code)]
+
 use std::prelude::rust_2021::*;
 
 // # DBus interface proxies for: `org.freedesktop.IBus.Panel`, `org.freedesktop.IBus`
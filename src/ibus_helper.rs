@@ -18,7 +18,7 @@ pub fn get_ibus_address() -> Result<String, String> {
     let mut split = display.split(":");
     let mut host = split.next().map_or_else(
         || Err(String::from("Failed to get host from display")),
-        |x| Ok(x),
+        Ok,
     )?;
     let disp_num = split.next().map_or_else(
         || {
@@ -29,11 +29,11 @@ pub fn get_ibus_address() -> Result<String, String> {
         |x| {
             x.split(".").next().map_or_else(
                 || Err("Failed to get display number from display (period)".into()),
-                |x| Ok(x),
+                Ok,
             )
         },
     )?;
-    if host.len() == 0 {
+    if host.is_empty() {
         host = "unix";
     }
 
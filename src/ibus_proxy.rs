@@ -1,71 +1,116 @@
+use async_trait::async_trait;
 use zbus::Connection;
 use zvariant::Value;
 
-use super::ibus_variants::{IBusLookupTable, IBusText};
+use super::ibus_variants::{IBusLookupTable, IBusPropList, IBusProperty, IBusText};
 
-static ENGINE_PATH: &str = "/org/freedesktop/IBus/Engine/FcPinyin";
 static ENGINE_IFACE: &str = "org.freedesktop.IBus.Engine";
 
+// The org.freedesktop.IBus.Engine signals the services send. IBusProxy emits them on the bus; tests
+// record them instead.
+#[async_trait]
+pub trait EngineSignals: Send + Sync {
+    async fn commit_text(&self, text: &str);
+    async fn update_preedit_text(&self, text: IBusText, cursor_pos: u32, visible: bool);
+    async fn update_lookup_table(&self, lt: IBusLookupTable, visible: bool);
+    async fn update_auxiliary_text(&self, text: &str, visible: bool);
+    async fn register_properties(&self, props: IBusPropList);
+    async fn update_property(&self, prop: IBusProperty);
+}
+
+// Emits the org.freedesktop.IBus.Engine signals of the engine object at `path`.
 pub struct IBusProxy {
     conn: Connection,
+    path: String,
 }
 
 impl IBusProxy {
-    pub fn new(conn_ref: &Connection) -> IBusProxy {
+    pub fn new(conn_ref: &Connection, path: &str) -> IBusProxy {
         IBusProxy {
             conn: conn_ref.clone(),
+            path: path.to_owned(),
         }
     }
 
-    pub async fn commit_text(&self, text: &str) {
-        commit_text(&self.conn, &Value::from(IBusText::from_str_ref(text).into_struct())).await;
+    #[allow(dead_code)]
+    pub async fn show_lookup_table(&self) {
+        show_lookup_table(&self.conn, &self.path).await;
+    }
+
+    #[allow(dead_code)]
+    pub async fn hide_lookup_table(&self) {
+        hide_lookup_table(&self.conn, &self.path).await;
+    }
+}
+
+#[async_trait]
+impl EngineSignals for IBusProxy {
+    async fn commit_text(&self, text: &str) {
+        commit_text(&self.conn, &self.path, &Value::from(IBusText::from_str_ref(text).into_struct())).await;
     }
 
-    pub async fn update_preedit_text(&self, text: &str, cursor_pos: u32, visible: bool) {
+    async fn update_preedit_text(&self, text: IBusText, cursor_pos: u32, visible: bool) {
         update_preedit_text(
             &self.conn,
-            &Value::from(IBusText::from_str_ref(text).into_struct()),
+            &self.path,
+            &Value::from(text.into_struct()),
             cursor_pos,
             visible,
         )
         .await;
     }
 
-    pub async fn update_lookup_table(&self, lt: IBusLookupTable, visible: bool) {
-        update_lookup_table(&self.conn, &Value::from(lt.into_struct()), visible).await;
+    async fn update_lookup_table(&self, lt: IBusLookupTable, visible: bool) {
+        update_lookup_table(&self.conn, &self.path, &Value::from(lt.into_struct()), visible).await;
     }
 
-    pub async fn show_lookup_table(&self) {
-        show_lookup_table(&self.conn).await;
+    async fn update_auxiliary_text(&self, text: &str, visible: bool) {
+        update_auxiliary_text(
+            &self.conn,
+            &self.path,
+            &Value::from(IBusText::from_str_ref(text).into_struct()),
+            visible,
+        )
+        .await;
     }
 
-    pub async fn hide_lookup_table(&self) {
-        hide_lookup_table(&self.conn).await;
+    async fn register_properties(&self, props: IBusPropList) {
+        register_properties(&self.conn, &self.path, &Value::from(props.into_struct())).await;
+    }
+
+    async fn update_property(&self, prop: IBusProperty) {
+        update_property(&self.conn, &self.path, &Value::from(prop.into_struct())).await;
     }
 }
 
-async fn commit_text(conn: &Connection, text: &Value<'_>) {
-    conn.emit_signal(None::<&str>, ENGINE_PATH, ENGINE_IFACE, "CommitText", text)
+async fn commit_text(conn: &Connection, path: &str, text: &Value<'_>) {
+    conn.emit_signal(None::<&str>, path, ENGINE_IFACE, "CommitText", text)
         .await
         .expect("Failed to emit CommitText signal.");
 }
 
-async fn update_preedit_text(conn: &Connection, text: &Value<'_>, cursor_pos: u32, visible: bool) {
+async fn update_preedit_text(
+    conn: &Connection,
+    path: &str,
+    text: &Value<'_>,
+    cursor_pos: u32,
+    visible: bool,
+) {
     conn.emit_signal(
         None::<&str>,
-        ENGINE_PATH,
+        path,
         ENGINE_IFACE,
         "UpdatePreeditText",
-        &(text, cursor_pos, visible, 0 as u32),
+        &(text, cursor_pos, visible, 0_u32),
     )
     .await
     .expect("Failed to emit UpdatePreeditText signal.");
 }
 
-async fn update_lookup_table(conn: &Connection, lt: &Value<'_>, visible: bool) {
+async fn update_lookup_table(conn: &Connection, path: &str, lt: &Value<'_>, visible: bool) {
     conn.emit_signal(
         None::<&str>,
-        ENGINE_PATH,
+        path,
         ENGINE_IFACE,
         "UpdateLookupTable",
         &(lt, visible),
@@ -74,10 +119,47 @@ async fn update_lookup_table(conn: &Connection, lt: &Value<'_>, visible: bool) {
     .expect("Failed to emit UpdateLookupTable signal.");
 }
 
-async fn show_lookup_table(conn: &Connection) {
+async fn update_auxiliary_text(conn: &Connection, path: &str, text: &Value<'_>, visible: bool) {
+    conn.emit_signal(
+        None::<&str>,
+        path,
+        ENGINE_IFACE,
+        "UpdateAuxiliaryText",
+        &(text, visible),
+    )
+    .await
+    .expect("Failed to emit UpdateAuxiliaryText signal.");
+}
+
+async fn register_properties(conn: &Connection, path: &str, props: &Value<'_>) {
+    conn.emit_signal(
+        None::<&str>,
+        path,
+        ENGINE_IFACE,
+        "RegisterProperties",
+        props,
+    )
+    .await
+    .expect("Failed to emit RegisterProperties signal.");
+}
+
+async fn update_property(conn: &Connection, path: &str, prop: &Value<'_>) {
+    conn.emit_signal(
+        None::<&str>,
+        path,
+        ENGINE_IFACE,
+        "UpdateProperty",
+        prop,
+    )
+    .await
+    .expect("Failed to emit UpdateProperty signal.");
+}
+
+#[allow(dead_code)]
+async fn show_lookup_table(conn: &Connection, path: &str) {
     conn.emit_signal(
         None::<&str>,
-        ENGINE_PATH,
+        path,
         ENGINE_IFACE,
         "ShowLookupTable",
         &(),
@@ -86,10 +168,11 @@ async fn show_lookup_table(conn: &Connection) {
     .expect("Failed to emit ShowLookupTable signal.");
 }
 
-async fn hide_lookup_table(conn: &Connection) {
+#[allow(dead_code)]
+async fn hide_lookup_table(conn: &Connection, path: &str) {
     conn.emit_signal(
         None::<&str>,
-        ENGINE_PATH,
+        path,
         ENGINE_IFACE,
         "HideLookupTable",
         &(),
@@ -97,3 +180,82 @@ async fn hide_lookup_table(conn: &Connection) {
     .await
     .expect("Failed to emit HideLookupTable signal.");
 }
+
+// Keeps the latest state of everything the services sent, for tests.
+#[cfg(test)]
+pub mod recording {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+
+    use super::EngineSignals;
+    use crate::ibus_variants::{IBusLookupTable, IBusPropList, IBusProperty, IBusText};
+
+    #[derive(Default)]
+    pub struct Recorded {
+        pub committed: Vec<String>,
+        pub preedit: Option<(IBusText, u32, bool)>,
+        pub lookup_table: Option<(IBusLookupTable, bool)>,
+        pub auxiliary_text: Option<(String, bool)>,
+    }
+
+    #[derive(Clone, Default)]
+    pub struct RecordingSignals {
+        pub recorded: Arc<Mutex<Recorded>>,
+    }
+
+    impl RecordingSignals {
+        pub fn committed(&self) -> Vec<String> {
+            self.recorded.lock().unwrap().committed.clone()
+        }
+
+        // The preedit shown and the cursor position in it.
+        pub fn preedit(&self) -> (String, u32) {
+            let recorded = self.recorded.lock().unwrap();
+            match &recorded.preedit {
+                Some((text, cursor, true)) => (text.text.clone(), *cursor),
+                _ => (String::new(), 0),
+            }
+        }
+
+        // The words on the page shown and the highlighted one's position on it.
+        pub fn page(&self) -> (Vec<String>, u32) {
+            let recorded = self.recorded.lock().unwrap();
+            match &recorded.lookup_table {
+                Some((lt, true)) => (lt.candidates().to_vec(), lt.cursor_pos()),
+                _ => (Vec::new(), 0),
+            }
+        }
+
+        pub fn auxiliary_text(&self) -> String {
+            let recorded = self.recorded.lock().unwrap();
+            match &recorded.auxiliary_text {
+                Some((text, true)) => text.clone(),
+                _ => String::new(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl EngineSignals for RecordingSignals {
+        async fn commit_text(&self, text: &str) {
+            self.recorded.lock().unwrap().committed.push(text.to_owned());
+        }
+
+        async fn update_preedit_text(&self, text: IBusText, cursor_pos: u32, visible: bool) {
+            self.recorded.lock().unwrap().preedit = Some((text, cursor_pos, visible));
+        }
+
+        async fn update_lookup_table(&self, lt: IBusLookupTable, visible: bool) {
+            self.recorded.lock().unwrap().lookup_table = Some((lt, visible));
+        }
+
+        async fn update_auxiliary_text(&self, text: &str, visible: bool) {
+            self.recorded.lock().unwrap().auxiliary_text = Some((text.to_owned(), visible));
+        }
+
+        async fn register_properties(&self, _props: IBusPropList) {}
+
+        async fn update_property(&self, _prop: IBusProperty) {}
+    }
+}
@@ -54,13 +54,14 @@ pub struct IBusComponent {
 }
 
 impl IBusComponent {
-    pub fn into_struct<'a>(self, engine_desc: IBusEngineDesc) -> Structure<'a> {
+    pub fn into_struct<'a>(self, engine_descs: Vec<IBusEngineDesc>) -> Structure<'a> {
         let sb: StructureBuilder = StructureBuilder::new();
         let attachments: HashMap<String, Value> = HashMap::new();
         let observed_paths: Vec<Value> = Vec::new();
         let mut engine_list: Vec<Value> = Vec::new();
-        let engine_desc_s = engine_desc.into_struct();
-        engine_list.push(Value::from(engine_desc_s));
+        for engine_desc in engine_descs {
+            engine_list.push(Value::from(engine_desc.into_struct()));
+        }
         let s = sb
             .add_field("IBusComponent")
             .add_field(attachments)
@@ -75,7 +76,7 @@ impl IBusComponent {
             .add_field(observed_paths)
             .add_field(engine_list)
             .build();
-        return s;
+        s
     }
 }
 
@@ -113,7 +114,7 @@ impl IBusEngineDesc {
             .add_field(self.author)
             .add_field(self.icon)
             .add_field(self.layout)
-            .add_field(self.rank as u32)
+            .add_field(self.rank)
             .add_field(self.hotkeys)
             .add_field(self.symbol)
             .add_field(self.setup)
@@ -122,7 +123,7 @@ impl IBusEngineDesc {
             .add_field(self.version)
             .add_field(self.textdomain)
             .build();
-        return s;
+        s
     }
 }
 
@@ -138,7 +139,8 @@ pub struct IBusLookupTable {
 }
 
 impl IBusLookupTable {
-    pub fn from_candidates(cands: &[Candidate]) -> IBusLookupTable {
+    // cursor_pos is relative to cands, i.e. to the page being shown.
+    pub fn from_candidates(cands: &[Candidate], cursor_pos: usize) -> IBusLookupTable {
         let mut candidates: Vec<String> = Vec::new();
         for cand in cands {
             candidates.push(cand.word.clone());
@@ -146,8 +148,8 @@ impl IBusLookupTable {
 
         IBusLookupTable {
             page_size: 5,
-            cursor_pos: 0,
-            cursor_visible: false,
+            cursor_pos: cursor_pos as u32,
+            cursor_visible: true,
             round: false,
             orientation: 2,
             candidates,
@@ -155,6 +157,24 @@ impl IBusLookupTable {
         }
     }
 
+    // Page size and the labels shown next to each candidate. Only as many labels as fit a page
+    // are sent.
+    pub fn with_layout(mut self, page_size: usize, labels: &[char]) -> IBusLookupTable {
+        self.page_size = page_size as u32;
+        self.labels = labels
+            .iter()
+            .take(page_size)
+            .map(|label| label.to_string())
+            .collect();
+        self
+    }
+
+    pub fn with_style(mut self, orientation: i32, round: bool) -> IBusLookupTable {
+        self.orientation = orientation;
+        self.round = round;
+        self
+    }
+
     pub fn from_nothing() -> IBusLookupTable {
         IBusLookupTable {
             page_size: 5,
@@ -167,6 +187,16 @@ impl IBusLookupTable {
         }
     }
 
+    #[cfg(test)]
+    pub fn candidates(&self) -> &[String] {
+        &self.candidates
+    }
+
+    #[cfg(test)]
+    pub fn cursor_pos(&self) -> u32 {
+        self.cursor_pos
+    }
+
     pub fn into_struct<'a>(self) -> Structure<'a> {
         let sb: StructureBuilder = StructureBuilder::new();
 
@@ -174,15 +204,18 @@ impl IBusLookupTable {
 
         let mut cand_texts: Vec<IBusText> = Vec::new();
         for cand in self.candidates {
-            cand_texts.push(IBusText { text: cand });
+            cand_texts.push(IBusText::from_str_ref(&cand));
         }
         let mut cands_v: Vec<Value> = Vec::new();
         for cand in cand_texts {
             cands_v.push(Value::from(cand.into_struct()));
         }
 
-        let labels_v: Vec<Value> = Vec::new();
         // According to the goibus implementation, Labels are also IBusText.
+        let mut labels_v: Vec<Value> = Vec::new();
+        for label in self.labels {
+            labels_v.push(Value::from(IBusText::from_str_ref(&label).into_struct()));
+        }
 
         let s = sb
             .add_field("IBusLookupTable")
@@ -195,52 +228,69 @@ impl IBusLookupTable {
             .add_field(cands_v)
             .add_field(labels_v)
             .build();
-        return s;
+        s
     }
 }
 
 pub struct IBusText {
     pub text: String,
+    pub attributes: Vec<IBusAttribute>,
 }
 
 impl IBusText {
     pub fn from_str_ref(content: &str) -> IBusText {
         IBusText {
-            text: content.to_string()
+            text: content.to_string(),
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn with_attributes(content: &str, attributes: Vec<IBusAttribute>) -> IBusText {
+        IBusText {
+            text: content.to_string(),
+            attributes,
         }
     }
 
     pub fn into_struct<'a>(self) -> Structure<'a> {
         let sb: StructureBuilder = StructureBuilder::new();
         let attachments: HashMap<String, Value> = HashMap::new();
-        let attribute_list = IBusAttrList {};
+        let attribute_list = IBusAttrList {
+            attributes: self.attributes,
+        };
         let s = sb
             .add_field("IBusText")
             .add_field(attachments)
             .add_field(self.text)
             .add_field(Value::from(attribute_list.into_struct()))
             .build();
-        return s;
+        s
     }
 }
 
-pub struct IBusAttrList {}
+pub struct IBusAttrList {
+    attributes: Vec<IBusAttribute>,
+}
 
 impl IBusAttrList {
     pub fn into_struct<'a>(self) -> Structure<'a> {
         let sb = StructureBuilder::new();
         let attachments: HashMap<String, Value> = HashMap::new();
-        let attribute_list: Vec<Value> = Vec::new();
+        let mut attribute_list: Vec<Value> = Vec::new();
+        for attribute in self.attributes {
+            attribute_list.push(Value::from(attribute.into_struct()));
+        }
         let s = sb
             .add_field("IBusAttrList")
             .add_field(attachments)
             .add_field(attribute_list)
             .build();
-        return s;
+        s
     }
 }
 
-// Currently not used because we can just use a Vec<Value> to represent attribute_list in IBusText.
+// Styles the chars from start_index (inclusive) to end_index (exclusive) of an IBusText.
+#[derive(Debug, PartialEq)]
 pub struct IBusAttribute {
     attribute_type: u32,
     value: u32,
@@ -248,17 +298,169 @@ pub struct IBusAttribute {
     end_index: u32,
 }
 
+// IBusAttrType
+const ATTR_TYPE_UNDERLINE: u32 = 1;
+const ATTR_TYPE_FOREGROUND: u32 = 2;
+const ATTR_TYPE_BACKGROUND: u32 = 3;
+
+// IBusAttrUnderline
+const ATTR_UNDERLINE_SINGLE: u32 = 1;
+
 impl IBusAttribute {
+    pub fn underline(start_index: usize, end_index: usize) -> IBusAttribute {
+        IBusAttribute {
+            attribute_type: ATTR_TYPE_UNDERLINE,
+            value: ATTR_UNDERLINE_SINGLE,
+            start_index: start_index as u32,
+            end_index: end_index as u32,
+        }
+    }
+
+    // rgb is 0xRRGGBB.
+    pub fn foreground(rgb: u32, start_index: usize, end_index: usize) -> IBusAttribute {
+        IBusAttribute {
+            attribute_type: ATTR_TYPE_FOREGROUND,
+            value: rgb,
+            start_index: start_index as u32,
+            end_index: end_index as u32,
+        }
+    }
+
+    pub fn background(rgb: u32, start_index: usize, end_index: usize) -> IBusAttribute {
+        IBusAttribute {
+            attribute_type: ATTR_TYPE_BACKGROUND,
+            value: rgb,
+            start_index: start_index as u32,
+            end_index: end_index as u32,
+        }
+    }
+
     pub fn into_struct<'a>(self) -> Structure<'a> {
         let sb = StructureBuilder::new();
+        let attachments: HashMap<String, Value> = HashMap::new();
         let s = sb
             .add_field("IBusAttribute")
-            .add_field(self.attribute_type as u32)
-            .add_field(self.value as u32)
-            .add_field(self.start_index as u32)
-            .add_field(self.end_index as u32)
+            .add_field(attachments)
+            .add_field(self.attribute_type)
+            .add_field(self.value)
+            .add_field(self.start_index)
+            .add_field(self.end_index)
             .build();
 
-        return s;
+        s
+    }
+}
+
+/*
+IBusProperty is what panels show as a button or menu item. Its wire format is:
+
+( <- struct
+    s <- name
+    a{sv} <- attachments
+    s <- key
+    u <- type: 0 normal, 1 toggle, 2 radio, 3 menu, 4 separator
+    v <- label (IBusText)
+    s <- icon
+    v <- tooltip (IBusText)
+    b <- sensitive
+    b <- visible
+    u <- state: 0 unchecked, 1 checked
+    v <- sub_props (IBusPropList)
+    v <- symbol (IBusText)
+)
+ */
+
+pub struct IBusProperty {
+    pub key: String,
+    pub prop_type: u32,
+    pub label: String,
+    pub icon: String,
+    pub tooltip: String,
+    pub sensitive: bool,
+    pub visible: bool,
+    pub state: u32,
+    pub symbol: String,
+}
+
+impl IBusProperty {
+    pub fn into_struct<'a>(self) -> Structure<'a> {
+        let sb = StructureBuilder::new();
+        let attachments: HashMap<String, Value> = HashMap::new();
+        let sub_props = IBusPropList { props: Vec::new() };
+        let s = sb
+            .add_field("IBusProperty")
+            .add_field(attachments)
+            .add_field(self.key)
+            .add_field(self.prop_type)
+            .add_field(Value::from(IBusText::from_str_ref(&self.label).into_struct()))
+            .add_field(self.icon)
+            .add_field(Value::from(IBusText::from_str_ref(&self.tooltip).into_struct()))
+            .add_field(self.sensitive)
+            .add_field(self.visible)
+            .add_field(self.state)
+            .add_field(Value::from(sub_props.into_struct()))
+            .add_field(Value::from(IBusText::from_str_ref(&self.symbol).into_struct()))
+            .build();
+        s
+    }
+}
+
+pub struct IBusPropList {
+    pub props: Vec<IBusProperty>,
+}
+
+impl IBusPropList {
+    pub fn into_struct<'a>(self) -> Structure<'a> {
+        let sb = StructureBuilder::new();
+        let attachments: HashMap<String, Value> = HashMap::new();
+        let mut props: Vec<Value> = Vec::new();
+        for prop in self.props {
+            props.push(Value::from(prop.into_struct()));
+        }
+        let s = sb
+            .add_field("IBusPropList")
+            .add_field(attachments)
+            .add_field(props)
+            .build();
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attribute_wire_format() {
+        let s = IBusAttribute::background(0xc8dcf0, 1, 3).into_struct();
+        assert_eq!(s.signature().as_str(), "(sa{sv}uuuu)");
+
+        let fields = s.fields();
+        assert_eq!(fields[0], Value::from("IBusAttribute"));
+        assert_eq!(fields[2], Value::from(ATTR_TYPE_BACKGROUND));
+        assert_eq!(fields[3], Value::from(0xc8dcf0u32));
+        assert_eq!(fields[4], Value::from(1u32));
+        assert_eq!(fields[5], Value::from(3u32));
+    }
+
+    #[test]
+    fn text_carries_its_attributes() {
+        let text = IBusText::with_attributes("ni'hao", vec![IBusAttribute::underline(0, 6)]);
+        let s = text.into_struct();
+        assert_eq!(s.signature().as_str(), "(sa{sv}sv)");
+
+        let attr_list = match &s.fields()[3] {
+            Value::Value(v) => match v.as_ref() {
+                Value::Structure(list) => list.clone(),
+                other => panic!("IBusAttrList isn't a struct: {:?}", other),
+            },
+            other => panic!("IBusAttrList isn't a variant: {:?}", other),
+        };
+        assert_eq!(attr_list.signature().as_str(), "(sa{sv}av)");
+        assert_eq!(attr_list.fields()[0], Value::from("IBusAttrList"));
+        match &attr_list.fields()[2] {
+            Value::Array(attributes) => assert_eq!(attributes.len(), 1),
+            other => panic!("The attributes aren't an array: {:?}", other),
+        }
     }
 }
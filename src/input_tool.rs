@@ -0,0 +1,178 @@
+use crate::{ibus_variants::IBusEngineDesc, keys::Key};
+
+// The Google Input Tools languages we register an IBus engine for. Each one has its own engine
+// name (what IBus lists in its menu), object path and `itc` code sent to the cloud.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputTool {
+    PinyinSimplified,
+    PinyinTraditional,
+    Jyutping,
+    Zhuyin,
+    JapaneseRomaji,
+}
+
+impl InputTool {
+    pub const ALL: [InputTool; 5] = [
+        InputTool::PinyinSimplified,
+        InputTool::PinyinTraditional,
+        InputTool::Jyutping,
+        InputTool::Zhuyin,
+        InputTool::JapaneseRomaji,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            InputTool::PinyinSimplified => "zh-t-i0-pinyin",
+            InputTool::PinyinTraditional => "zh-hant-t-i0-pinyin",
+            InputTool::Jyutping => "yue-hant-t-i0-und",
+            InputTool::Zhuyin => "zh-hant-t-i0-und",
+            InputTool::JapaneseRomaji => "ja-t-ja-hira-i0-und",
+        }
+    }
+
+    // Must match the <name> of the engines in fcpinyin.xml.
+    pub fn engine_name(self) -> &'static str {
+        match self {
+            InputTool::PinyinSimplified => "fcpinyin",
+            InputTool::PinyinTraditional => "fcpinyin-hant",
+            InputTool::Jyutping => "fcjyutping",
+            InputTool::Zhuyin => "fczhuyin",
+            InputTool::JapaneseRomaji => "fcromaji",
+        }
+    }
+
+    // Whether typed letters are Mandarin pinyin, which we can split into syllables ourselves.
+    pub fn is_pinyin(self) -> bool {
+        matches!(self, InputTool::PinyinSimplified | InputTool::PinyinTraditional)
+    }
+
+    // Keys besides letters that spell in this input tool, so go into the preedit instead of
+    // selecting, paging or typing symbols. Zhuyin is typed on the Dachen layout (e.g. su3cl3 for
+    // 你好), romaji uses - for the long vowel mark.
+    pub fn spells_with(self, key: Key) -> bool {
+        match self {
+            InputTool::Zhuyin => {
+                key.to_usize().is_some()
+                    || matches!(
+                        key,
+                        Key::Minus | Key::Comma | Key::Period | Key::Slash | Key::SemiColon
+                    )
+            }
+            InputTool::JapaneseRomaji => key == Key::Minus,
+            _ => false,
+        }
+    }
+
+    pub fn from_engine_name(name: &str) -> Option<InputTool> {
+        InputTool::ALL
+            .into_iter()
+            .find(|tool| tool.engine_name() == name)
+    }
+
+    pub fn object_path(self) -> &'static str {
+        match self {
+            InputTool::PinyinSimplified => "/org/freedesktop/IBus/Engine/FcPinyin",
+            InputTool::PinyinTraditional => "/org/freedesktop/IBus/Engine/FcPinyinHant",
+            InputTool::Jyutping => "/org/freedesktop/IBus/Engine/FcJyutping",
+            InputTool::Zhuyin => "/org/freedesktop/IBus/Engine/FcZhuyin",
+            InputTool::JapaneseRomaji => "/org/freedesktop/IBus/Engine/FcRomaji",
+        }
+    }
+
+    // Must match the <engine> entries in fcpinyin.xml, which IBus reads when it starts us.
+    pub fn engine_desc(self) -> IBusEngineDesc {
+        let (long_name, description, language, symbol, rank) = match self {
+            InputTool::PinyinSimplified => (
+                "Full Cloud Pinyin",
+                "Use Google's cloud pinyin as backend",
+                "zh_CN",
+                "云",
+                99,
+            ),
+            InputTool::PinyinTraditional => (
+                "Full Cloud Pinyin (Traditional)",
+                "Traditional Chinese pinyin using Google's cloud as backend",
+                "zh_TW",
+                "雲",
+                0,
+            ),
+            InputTool::Jyutping => (
+                "Full Cloud Jyutping",
+                "Cantonese jyutping using Google's cloud as backend",
+                "zh_HK",
+                "粵",
+                0,
+            ),
+            InputTool::Zhuyin => (
+                "Full Cloud Zhuyin",
+                "Zhuyin using Google's cloud as backend",
+                "zh_TW",
+                "注",
+                0,
+            ),
+            InputTool::JapaneseRomaji => (
+                "Full Cloud Romaji",
+                "Japanese romaji using Google's cloud as backend",
+                "ja",
+                "あ",
+                0,
+            ),
+        };
+
+        IBusEngineDesc {
+            engine_name: self.engine_name().to_owned(),
+            long_name: long_name.to_owned(),
+            description: description.to_owned(),
+            language: language.to_owned(),
+            license: "MIT".to_owned(),
+            author: "Qingxiang Jia".to_owned(),
+            icon: "/usr/share/icons/breeze/emblems/24@3x/emblem-checked.svg".to_owned(),
+            layout: "default".to_owned(),
+            rank,
+            hotkeys: "".to_owned(),
+            symbol: symbol.to_owned(),
+            setup: "/usr/bin/gittupref".to_owned(),
+            layout_variant: "".to_owned(),
+            layout_option: "".to_owned(),
+            version: "0.1".to_owned(),
+            textdomain: "full-cloud-pinyin".to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The text of <tag> in the <engine> entry named name.
+    fn xml_field(name: &str, tag: &str) -> String {
+        let xml = include_str!("../fcpinyin.xml");
+        let engine = xml
+            .split("<engine>")
+            .find(|engine| engine.contains(&format!("<name>{}</name>", name)))
+            .unwrap_or_else(|| panic!("{} is not in fcpinyin.xml", name));
+        let start = engine.find(&format!("<{}>", tag)).unwrap() + tag.len() + 2;
+        let end = engine.find(&format!("</{}>", tag)).unwrap();
+        engine[start..end].trim().to_owned()
+    }
+
+    #[test]
+    fn engine_descs_match_the_component_file() {
+        for tool in InputTool::ALL {
+            let desc = tool.engine_desc();
+            let name = &desc.engine_name;
+
+            assert_eq!(desc.long_name, xml_field(name, "longname"));
+            assert_eq!(desc.description, xml_field(name, "description"));
+            assert_eq!(desc.language, xml_field(name, "language"));
+            assert_eq!(desc.license, xml_field(name, "license"));
+            assert_eq!(desc.author, xml_field(name, "author"));
+            assert_eq!(desc.icon, xml_field(name, "icon"));
+            assert_eq!(desc.layout, xml_field(name, "layout"));
+            assert_eq!(desc.rank.to_string(), xml_field(name, "rank"));
+            assert_eq!(desc.symbol, xml_field(name, "symbol"));
+            assert_eq!(desc.setup, xml_field(name, "setup"));
+            assert_eq!(desc.textdomain, xml_field(name, "textdomain"));
+        }
+    }
+}
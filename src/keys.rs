@@ -73,10 +73,16 @@ pub enum Key {
     Right = 65363,
     Backspace = 65288,
     Escape = 65307,
+    Home = 65360,
+    End = 65367,
+    Delete = 65535,
     Shift = 65505,
     Ctrl = 65507,
     Alt = 65513,
     BackSlash = 92,
+    Slash = 47,
+    PageUp = 65365,
+    PageDown = 65366,
     ExclamationMark = 33,
     Ellipsis = 94,
     _0 = 48,
@@ -139,10 +145,16 @@ impl Key {
             65363 => Some(Key::Right),
             65288 => Some(Key::Backspace),
             65307 => Some(Key::Escape),
+            65360 => Some(Key::Home),
+            65367 => Some(Key::End),
+            65535 => Some(Key::Delete),
             65505 => Some(Key::Shift),
             65507 => Some(Key::Ctrl),
             65513 => Some(Key::Alt),
             92 => Some(Key::BackSlash),
+            47 => Some(Key::Slash),
+            65365 => Some(Key::PageUp),
+            65366 => Some(Key::PageDown),
             33 => Some(Key::ExclamationMark),
             94 => Some(Key::Ellipsis),
             48 => Some(Key::_0),
@@ -213,6 +225,21 @@ impl Key {
             Key::X => Some('X'),
             Key::Y => Some('Y'),
             Key::Z => Some('Z'),
+            Key::_0 => Some('0'),
+            Key::_1 => Some('1'),
+            Key::_2 => Some('2'),
+            Key::_3 => Some('3'),
+            Key::_4 => Some('4'),
+            Key::_5 => Some('5'),
+            Key::_6 => Some('6'),
+            Key::_7 => Some('7'),
+            Key::_8 => Some('8'),
+            Key::_9 => Some('9'),
+            Key::Minus => Some('-'),
+            Key::Comma => Some(','),
+            Key::Period => Some('.'),
+            Key::Slash => Some('/'),
+            Key::SemiColon => Some(';'),
             _ => None,
         }
     }
@@ -1,20 +1,76 @@
-use zbus::{dbus_interface, Connection};
-use zvariant::ObjectPath;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
 
-use crate::pipeline::Pipeline;
+use zbus::{dbus_interface, fdo, Connection, MessageHeader, ObjectServer};
+use zvariant::OwnedObjectPath;
+
+use crate::{
+    candidate_backend::Backends, config::Config, input_tool::InputTool, pipeline::Pipeline,
+    script_converter::ScriptConverter,
+};
 
 // We have three interfaces to implement in order to get a working engine, but only the
 // org.freedesktop.IBus.Engine matters in practice.
 
 // Implementation of org.freedesktop.IBus.Factory interface
 
-pub struct FactoryListener {}
+// IBus asks for one engine per input context, so every window gets its own object (and with it
+// its own mode, preedit and candidates). Only the backends and conversion tables are shared.
+pub struct FactoryListener {
+    backends: Vec<(InputTool, Backends)>,
+    converter: Option<Arc<ScriptConverter>>,
+    config: Config,
+    next_id: AtomicU32,
+}
+
+impl FactoryListener {
+    pub fn new(
+        backends: Vec<(InputTool, Backends)>,
+        converter: Option<Arc<ScriptConverter>>,
+        config: Config,
+    ) -> FactoryListener {
+        FactoryListener {
+            backends,
+            converter,
+            config,
+            next_id: AtomicU32::new(1),
+        }
+    }
+}
 
 #[dbus_interface(name = "org.freedesktop.IBus.Factory")]
 impl FactoryListener {
-    pub fn create_engine(&self, _name: &str) -> ObjectPath {
-        println!("create_engine called by IBus.");
-        ObjectPath::from_str_unchecked("/org/freedesktop/IBus/Engine/FcPinyin")
+    pub async fn create_engine(
+        &self,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(object_server)] server: &ObjectServer,
+        name: &str,
+    ) -> fdo::Result<OwnedObjectPath> {
+        let tool = InputTool::from_engine_name(name).unwrap_or(InputTool::PinyinSimplified);
+        let backends = self
+            .backends
+            .iter()
+            .find(|(t, _)| *t == tool)
+            .map(|(_, backends)| backends.clone())
+            .ok_or_else(|| fdo::Error::Failed(format!("No backends for engine {}.", name)))?;
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let path = format!("{}/{}", tool.object_path(), id);
+        println!("create_engine called by IBus for {}, serving it at {}.", name, path);
+
+        let listener = new_input_listener(
+            conn,
+            &path,
+            backends,
+            self.converter.clone(),
+            &self.config,
+        );
+        server.at(path.as_str(), listener).await?;
+        server.at(path.as_str(), ServiceListener {}).await?;
+
+        OwnedObjectPath::try_from(path).map_err(|e| fdo::Error::Failed(e.to_string()))
     }
 }
 
@@ -24,8 +80,23 @@ pub struct ServiceListener {}
 
 #[dbus_interface(name = "org.freedesktop.IBus.Service")]
 impl ServiceListener {
-    pub fn destroy(&self) {
-        println!("destroy called by IBus.");
+    // Called on the engine's own path when its input context goes away.
+    pub async fn destroy(
+        &self,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) {
+        let path = match header.path() {
+            Ok(Some(path)) => path.to_owned(),
+            _ => return,
+        };
+        println!("destroy called by IBus for {}.", path);
+
+        if let Ok(engine) = server.interface::<_, InputListener>(&path).await {
+            engine.get().await.pipeline.destroy();
+            let _ = server.remove::<InputListener, _>(&path).await;
+        }
+        let _ = server.remove::<ServiceListener, _>(&path).await;
     }
 }
 
@@ -40,10 +111,66 @@ impl InputListener {
         // println!("keyval: {keyval}, keycode: {keycode}, state: {bi}");
         self.pipeline.accept(keyval, keycode, state).await
     }
+
+    pub async fn focus_in(&self) {
+        self.pipeline.focus_in().await;
+    }
+
+    pub async fn focus_out(&self) {
+        self.pipeline.focus_out().await;
+    }
+
+    // Sent when the application resets its input context, e.g. after the cursor was moved with
+    // the mouse. The composition no longer belongs anywhere, so it is dropped.
+    pub async fn reset(&self) {
+        self.pipeline.reset().await;
+    }
+
+    pub async fn enable(&self) {
+        self.pipeline.focus_in().await;
+    }
+
+    pub async fn disable(&self) {
+        self.pipeline.focus_out().await;
+    }
+
+    pub async fn candidate_clicked(&self, index: u32, button: u32, _state: u32) {
+        // Only the primary button selects; panels use the others for context menus.
+        if button == 1 {
+            self.pipeline.candidate_clicked(index).await;
+        }
+    }
+
+    // The arrows on the panel.
+    pub async fn page_up(&self) {
+        self.pipeline.page_up().await;
+    }
+
+    pub async fn page_down(&self) {
+        self.pipeline.page_down().await;
+    }
+
+    pub async fn cursor_up(&self) {
+        self.pipeline.cursor_up().await;
+    }
+
+    pub async fn cursor_down(&self) {
+        self.pipeline.cursor_down().await;
+    }
+
+    pub async fn property_activate(&self, name: &str, _state: u32) {
+        self.pipeline.property_activate(name).await;
+    }
 }
 
-pub fn new_input_listener(conn: &Connection) -> InputListener {
+pub fn new_input_listener(
+    conn: &Connection,
+    path: &str,
+    backends: Backends,
+    converter: Option<Arc<ScriptConverter>>,
+    config: &Config,
+) -> InputListener {
     InputListener {
-        pipeline: Pipeline::new(conn)
+        pipeline: Pipeline::new(conn, path, backends, converter, config)
     }
 }
@@ -0,0 +1,236 @@
+use std::{collections::HashMap, path::PathBuf, time::UNIX_EPOCH};
+
+use async_trait::async_trait;
+
+use crate::{
+    candidate::Candidate,
+    candidate_backend::{CandidateBackend, QueryError},
+    config::{config_dir, data_dir, DictionaryConfig},
+};
+
+static BUNDLED_LEXICON: &str = include_str!("../data/lexicon.txt");
+
+// A small pinyin -> hanzi lexicon kept in sled, used when the cloud cannot be reached.
+//
+// The lexicon is built from the bundled data/lexicon.txt plus an optional user file with the same
+// format (`<pinyin> <word> [weight]` per line, `#` starts a comment). It is only re-imported when
+// one of the sources changes.
+pub struct LocalDictionary {
+    lexicon: sled::Tree,
+}
+
+impl LocalDictionary {
+    pub fn open(config: &DictionaryConfig) -> Option<LocalDictionary> {
+        if !config.enabled {
+            return None;
+        }
+
+        let path = data_dir()?.join("lexicon");
+        let db = match sled::open(&path) {
+            Ok(db) => db,
+            Err(e) => {
+                println!("Failed to open the local dictionary at {:?}: {}", path, e);
+                return None;
+            }
+        };
+        let lexicon = db.open_tree("lexicon").ok()?;
+        let meta = db.open_tree("meta").ok()?;
+
+        let user_path = config
+            .path
+            .clone()
+            .or_else(|| config_dir().map(|dir| dir.join("lexicon.txt")));
+        let fingerprint = fingerprint(user_path.as_ref());
+
+        let up_to_date =
+            matches!(meta.get("fingerprint"), Ok(Some(v)) if v == fingerprint.as_bytes());
+        if !up_to_date {
+            let mut sources = vec![BUNDLED_LEXICON.to_owned()];
+            if let Some(text) = user_path.and_then(|p| std::fs::read_to_string(p).ok()) {
+                sources.push(text);
+            }
+            if let Err(e) = import(&lexicon, &sources) {
+                println!("Failed to import the local dictionary: {}", e);
+                return None;
+            }
+            let _ = meta.insert("fingerprint", fingerprint.as_bytes());
+        }
+
+        Some(LocalDictionary { lexicon })
+    }
+
+    // Whole-preedit matches come first, then words for ever shorter prefixes. The latter carry a
+    // matched_len so only the pinyin they cover is consumed when selected. Syllable separators
+    // (xi'an) are ignored for the lookup but counted in matched_len, like the cloud does.
+    pub fn lookup(&self, preedit: &str, depth: usize) -> Vec<Candidate> {
+        let mut candidates: Vec<Candidate> = Vec::new();
+
+        for end in (1..=preedit.len()).rev() {
+            if !preedit.is_char_boundary(end) || preedit[..end].ends_with('\'') {
+                continue;
+            }
+            let prefix = preedit[..end].replace('\'', "");
+            let prefix = prefix.as_str();
+            let words: Vec<(String, u32)> = match self.lexicon.get(prefix) {
+                Ok(Some(value)) => bincode::deserialize(&value).unwrap_or_default(),
+                _ => continue,
+            };
+
+            for (word, _) in words {
+                if candidates.len() >= depth {
+                    return candidates;
+                }
+                candidates.push(Candidate {
+                    word,
+                    annotation: preedit[..end].to_owned(),
+                    matched_len: Some(preedit[..end].chars().count() as i32),
+                });
+            }
+        }
+
+        candidates
+    }
+}
+
+#[async_trait]
+impl CandidateBackend for LocalDictionary {
+    async fn query_candidates(
+        &self,
+        preedit: &str,
+        depth: usize,
+    ) -> Result<Vec<Candidate>, QueryError> {
+        Ok(self.lookup(preedit, depth))
+    }
+
+    fn input_tool(&self) -> &str {
+        "local"
+    }
+}
+
+fn import(lexicon: &sled::Tree, sources: &[String]) -> sled::Result<()> {
+    let mut entries: HashMap<String, Vec<(String, u32)>> = HashMap::new();
+
+    for source in sources {
+        for line in source.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (pinyin, word) = match (fields.next(), fields.next()) {
+                (Some(pinyin), Some(word)) => (pinyin, word),
+                _ => continue,
+            };
+            let weight = fields.next().and_then(|w| w.parse().ok()).unwrap_or(0);
+
+            let words = entries.entry(pinyin.to_lowercase()).or_default();
+            match words.iter_mut().find(|(w, _)| w == word) {
+                Some(existing) => existing.1 = weight,
+                None => words.push((word.to_owned(), weight)),
+            }
+        }
+    }
+
+    lexicon.clear()?;
+    let mut batch = sled::Batch::default();
+    for (pinyin, mut words) in entries {
+        words.sort_by_key(|(_, weight)| std::cmp::Reverse(*weight));
+        if let Ok(value) = bincode::serialize(&words) {
+            batch.insert(pinyin.as_bytes(), value);
+        }
+    }
+    lexicon.apply_batch(batch)
+}
+
+// Changes whenever the bundled lexicon or the user file changes.
+fn fingerprint(user_path: Option<&PathBuf>) -> String {
+    let user = user_path
+        .and_then(|p| std::fs::metadata(p).ok())
+        .map(|m| {
+            let modified = m
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            format!("{}:{}", m.len(), modified)
+        })
+        .unwrap_or_default();
+    format!("{:016x}|{:?}|{}", fnv1a(BUNDLED_LEXICON), user_path, user)
+}
+
+// 64-bit FNV-1a. Unlike std's DefaultHasher its output is fixed, so upgrading Rust doesn't force a
+// re-import.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dictionary(source: &str) -> LocalDictionary {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let lexicon = db.open_tree("lexicon").unwrap();
+        import(&lexicon, &[source.to_owned()]).unwrap();
+        LocalDictionary { lexicon }
+    }
+
+    fn lookup(dictionary: &LocalDictionary, preedit: &str) -> Vec<(String, Option<i32>)> {
+        dictionary
+            .lookup(preedit, 11)
+            .into_iter()
+            .map(|c| (c.word, c.matched_len))
+            .collect()
+    }
+
+    #[test]
+    fn whole_matches_come_before_prefixes() {
+        let dictionary = dictionary("ni 你 10\nni 尼 5\nnihao 你好 10\nhao 好 10");
+
+        assert_eq!(
+            lookup(&dictionary, "nihao"),
+            [
+                ("你好".to_owned(), Some(5)),
+                ("你".to_owned(), Some(2)),
+                ("尼".to_owned(), Some(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn separators_are_ignored_but_counted() {
+        let dictionary = dictionary("xian 先 10\nxi 西 10");
+
+        assert_eq!(
+            lookup(&dictionary, "xi'an"),
+            [("先".to_owned(), Some(5)), ("西".to_owned(), Some(2))]
+        );
+    }
+
+    #[test]
+    fn stops_at_depth() {
+        let dictionary = dictionary("ni 你 10\nni 尼 5\nni 泥 1");
+
+        assert_eq!(dictionary.lookup("ni", 2).len(), 2);
+    }
+
+    #[test]
+    fn user_entries_reweight_bundled_ones() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let lexicon = db.open_tree("lexicon").unwrap();
+        import(&lexicon, &["ni 你 10\nni 尼 5".to_owned(), "ni 尼 20".to_owned()]).unwrap();
+        let dictionary = LocalDictionary { lexicon };
+
+        let words: Vec<String> = dictionary.lookup("ni", 11).into_iter().map(|c| c.word).collect();
+        assert_eq!(words, ["尼", "你"]);
+    }
+
+    #[test]
+    fn fingerprint_follows_the_contents() {
+        assert_eq!(fnv1a(""), 0xcbf29ce484222325);
+        assert_ne!(fnv1a("ni 你 10"), fnv1a("ni 尼 10"));
+    }
+}
@@ -1,11 +1,16 @@
 extern crate std;
 
-use std::env;
+use std::{env, sync::Arc};
 
 use crate::{generated::IBusProxyGen, ibus_helper::get_ibus_address};
 
-use ibus_variants::{IBusEngineDesc, IBusComponent};
-use listeners::{new_input_listener, FactoryListener, ServiceListener};
+use candidate_backend::Backends;
+use config::Config;
+
+use ibus_variants::IBusComponent;
+use input_tool::InputTool;
+use script_converter::ScriptConverter;
+use listeners::{FactoryListener, ServiceListener};
 use zbus::{zvariant::Value, ConnectionBuilder};
 
 mod generated;
@@ -15,6 +20,8 @@ mod listeners;
 mod mode_switcher;
 mod dispatcher;
 mod candidate;
+mod candidate_backend;
+mod config;
 mod pipeline;
 mod cloud_pinyin_client;
 mod candidate_service;
@@ -23,27 +30,44 @@ mod symbol_service;
 mod number_service;
 mod ibus_proxy;
 mod ibus_variants;
+mod input_tool;
+mod local_dictionary;
+mod query_cache;
+mod script_converter;
+mod pinyin_segmenter;
 
 #[tokio::main]
 async fn main() {
     let mut run_by_ibus = false;
+    let mut config = Config::load();
 
     let args: Vec<String> = env::args().collect();
     for arg in &args {
         if arg == "ibus" {
             run_by_ibus = true;
-            break;
+        } else if let Some(backend) = arg.strip_prefix("--backend=") {
+            match backend.parse() {
+                Ok(kind) => config.backend = kind,
+                Err(e) => println!("{}", e),
+            }
         }
     }
 
+    let backends = Backends::for_input_tools(&config);
+    let converter = ScriptConverter::load(&config.conversion).map(Arc::new);
+
     if run_by_ibus {
-        start_from_ibus().await;
+        start_from_ibus(backends, converter, config).await;
     } else {
-        start_from_console().await;
+        start_from_console(backends, converter, config).await;
     }
 }
 
-async fn start_from_ibus() {
+async fn start_from_ibus(
+    backends: Vec<(InputTool, Backends)>,
+    converter: Option<Arc<ScriptConverter>>,
+    config: Config,
+) {
     let address = get_ibus_address().expect("Failed to get IBus address.");
 
     let conn = ConnectionBuilder::address(address.to_owned().as_str())
@@ -52,15 +76,10 @@ async fn start_from_ibus() {
         .await
         .expect("Failed to build connection to IBus.");
 
-    conn.object_server()
-        .at("/org/freedesktop/IBus/Factory", FactoryListener {})
-        .await
-        .expect("Faild to set up server object.");
-
     conn.object_server()
         .at(
-            "/org/freedesktop/IBus/Engine/FcPinyin",
-            new_input_listener(&conn),
+            "/org/freedesktop/IBus/Factory",
+            FactoryListener::new(backends, converter, config),
         )
         .await
         .expect("Faild to set up server object.");
@@ -84,26 +103,11 @@ async fn start_from_ibus() {
     }
 }
 
-async fn start_from_console() {
-    let ibus_engine_desc = IBusEngineDesc {
-        engine_name: "full-cloud-pinyin".to_owned(),
-        long_name: "Full Cloud Pinyin".to_owned(),
-        description: "The Full Cloud Pinyin input method".to_owned(),
-        language: "en".to_owned(),
-        license: "MIT".to_owned(),
-        author: "Qingxiang Jia".to_owned(),
-        icon: "/usr/share/icons/breeze/emblems/24@3x/emblem-checked.svg".to_owned(),
-        layout: "en".to_owned(),
-        rank: 0,
-        hotkeys: "".to_owned(),
-        symbol: "".to_owned(),
-        setup: "/usr/bin/gittupref".to_owned(),
-        layout_variant: "".to_owned(),
-        layout_option: "".to_owned(),
-        version: "0.1".to_owned(),
-        textdomain: "full-cloud-pinyin".to_owned(),
-    };
-
+async fn start_from_console(
+    backends: Vec<(InputTool, Backends)>,
+    converter: Option<Arc<ScriptConverter>>,
+    config: Config,
+) {
     let ibus_component = IBusComponent {
         component_name: "org.freedesktop.IBus.FcPinyin".to_owned(),
         description: "".to_owned(),
@@ -115,7 +119,8 @@ async fn start_from_console() {
         textdomain: "".to_owned(),
     };
 
-    let component_to_reg = (ibus_component).into_struct(ibus_engine_desc);
+    let engine_descs = InputTool::ALL.into_iter().map(InputTool::engine_desc).collect();
+    let component_to_reg = (ibus_component).into_struct(engine_descs);
 
     let address = get_ibus_address().expect("Failed to get IBus address.");
 
@@ -129,15 +134,10 @@ async fn start_from_console() {
         .await
         .expect("Failed to create IBusProxy.");
 
-    conn.object_server()
-        .at("/org/freedesktop/IBus/Factory", FactoryListener {})
-        .await
-        .expect("Faild to set up server object.");
-
     conn.object_server()
         .at(
-            "/org/freedesktop/IBus/Engine/FcPinyin",
-            new_input_listener(&conn),
+            "/org/freedesktop/IBus/Factory",
+            FactoryListener::new(backends, converter, config),
         )
         .await
         .expect("Faild to set up server object.");
@@ -162,7 +162,7 @@ async fn start_from_console() {
         }
     }
 
-    ibus.set_global_engine("full-cloud-pinyin")
+    ibus.set_global_engine(InputTool::PinyinSimplified.engine_name())
         .await
         .expect("Failed to call set_global_engine.");
 
@@ -7,6 +7,7 @@ use tokio::sync::Mutex;
 
 use crate::keys::Key;
 
+#[allow(dead_code)]
 #[derive(Clone)]
 pub struct KeyContent{
     pub key : Key,
@@ -17,11 +18,14 @@ pub struct KeyContent{
 pub struct ModeSwitcher {
     mode: Arc<Mutex<Mode>>,
     last: Arc<Mutex<KeyContent>>,
+    // Toggles simplified/traditional output. None if turned off or there is nothing to toggle.
+    toggle_script: Option<Hotkey>,
 }
 
 impl ModeSwitcher {
-    pub fn new() -> ModeSwitcher {
+    pub fn new(toggle_script: Option<Hotkey>) -> ModeSwitcher {
         ModeSwitcher {
+            toggle_script,
             mode: Arc::new(Mutex::new(Mode::English)),
             last: Arc::new(Mutex::new(KeyContent{
                 key : Key::a,
@@ -50,6 +54,19 @@ impl ModeSwitcher {
         state: u32,
     ) -> ModeSwitcherReturn {
 
+        let flags = self.decode_flag(state);
+
+        // Checked on the keyval, as Shift turns f into F, which isn't a Key. In English mode the
+        // shortcut is the application's.
+        if let Some(hotkey) = &self.toggle_script {
+            if hotkey.matches(keyval, &flags) && self.mode().await == Mode::Pinyin {
+                if flags.is_release {
+                    return ModeSwitcherReturn::Done(true);
+                }
+                return ModeSwitcherReturn::ToggleScript;
+            }
+        }
+
         let key = match Key::from_u32(keyval) {
             Some(key) => key,
             None => {
@@ -57,9 +74,8 @@ impl ModeSwitcher {
             },
         };
         let last = self.last().await;
-        let flags = self.decode_flag(state);
         let key_content = KeyContent { 
-            key: key, 
+            key, 
             flags: flags.clone(), 
             key_code: keycode 
         };
@@ -77,6 +93,11 @@ impl ModeSwitcher {
             return ModeSwitcherReturn::Done(false);
         }
 
+        // Other Ctrl combinations belong to the application, e.g. Ctrl+C.
+        if flags.is_ctrl {
+            return ModeSwitcherReturn::Done(false);
+        }
+
         if (key_content.key == Key::Shift) && (key_content.flags.is_release) 
             && (last.key == Key::Shift) && (!last.flags.is_release)
         {
@@ -101,7 +122,7 @@ impl ModeSwitcher {
 
     async fn mode(&self) -> Mode {
         let x = self.mode.lock().await;
-        x.clone()
+        *x
     }
 
     async fn set_mode(&self, val: Mode) {
@@ -153,6 +174,69 @@ pub enum ModeSwitcherReturn {
     Continue(KeyContent),
     Done(bool),
     SwitchMode,
+    ToggleScript,
+}
+
+// A shortcut such as Ctrl+Shift+F: Ctrl and/or Alt, maybe Shift, and a letter or digit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hotkey {
+    ctrl: bool,
+    shift: bool,
+    alt: bool,
+    key: char,
+}
+
+impl Hotkey {
+    // Reads shortcuts written like "ctrl+shift+f", in any case.
+    pub fn parse(text: &str) -> Option<Hotkey> {
+        let mut parts: Vec<String> = text.split('+').map(|p| p.trim().to_lowercase()).collect();
+        let last = parts.pop()?;
+        let mut key = last.chars();
+        let mut hotkey = Hotkey {
+            ctrl: false,
+            shift: false,
+            alt: false,
+            key: key.next()?,
+        };
+        if key.next().is_some() || !hotkey.key.is_ascii_alphanumeric() {
+            return None;
+        }
+        for part in parts {
+            match part.as_str() {
+                "ctrl" => hotkey.ctrl = true,
+                "shift" => hotkey.shift = true,
+                "alt" => hotkey.alt = true,
+                _ => return None,
+            }
+        }
+        // Without Ctrl or Alt it would be typed.
+        if !hotkey.ctrl && !hotkey.alt {
+            return None;
+        }
+        Some(hotkey)
+    }
+
+    pub fn matches(&self, keyval: u32, flags: &Flags) -> bool {
+        char::from_u32(keyval).map(|c| c.to_ascii_lowercase()) == Some(self.key)
+            && flags.is_ctrl == self.ctrl
+            && flags.is_shift == self.shift
+            && flags.is_alt == self.alt
+    }
+}
+
+impl fmt::Display for Hotkey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.ctrl {
+            write!(f, "Ctrl+")?;
+        }
+        if self.alt {
+            write!(f, "Alt+")?;
+        }
+        if self.shift {
+            write!(f, "Shift+")?;
+        }
+        write!(f, "{}", self.key.to_ascii_uppercase())
+    }
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -161,6 +245,7 @@ enum Mode {
     Pinyin,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Default,Clone)]
 pub struct Flags {
     pub is_shift: bool,
@@ -193,3 +278,60 @@ impl fmt::Display for Flags {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHIFT: u32 = 1;
+    const CTRL: u32 = 1 << 2;
+    const RELEASE: u32 = 1 << 30;
+    const SHIFT_L: u32 = 65505;
+    const F: u32 = 70;
+
+    // Tapping Shift leaves English mode.
+    async fn in_pinyin_mode(toggle_script: Option<Hotkey>) -> ModeSwitcher {
+        let switcher = ModeSwitcher::new(toggle_script);
+        switcher.process_key_event(SHIFT_L, 0, 0).await;
+        let output = switcher.process_key_event(SHIFT_L, 0, SHIFT | RELEASE).await;
+        assert!(matches!(output, ModeSwitcherReturn::SwitchMode));
+        switcher
+    }
+
+    #[test]
+    fn parses_hotkeys() {
+        let hotkey = Hotkey::parse("ctrl+shift+f").unwrap();
+        assert_eq!(hotkey.to_string(), "Ctrl+Shift+F");
+        assert_eq!(Hotkey::parse(" Alt + T "), Hotkey::parse("alt+t"));
+        assert_eq!(Hotkey::parse(""), None);
+        assert_eq!(Hotkey::parse("shift+f"), None);
+        assert_eq!(Hotkey::parse("ctrl+super+f"), None);
+        assert_eq!(Hotkey::parse("ctrl+f1"), None);
+    }
+
+    #[tokio::test]
+    async fn hotkey_toggles_the_script() {
+        let switcher = in_pinyin_mode(Hotkey::parse("ctrl+shift+f")).await;
+
+        let output = switcher.process_key_event(F, 0, CTRL | SHIFT).await;
+        assert!(matches!(output, ModeSwitcherReturn::ToggleScript));
+        let output = switcher.process_key_event(F, 0, CTRL | SHIFT | RELEASE).await;
+        assert!(matches!(output, ModeSwitcherReturn::Done(true)));
+    }
+
+    #[tokio::test]
+    async fn hotkey_is_the_applications_in_english_mode() {
+        let switcher = ModeSwitcher::new(Hotkey::parse("ctrl+shift+f"));
+
+        let output = switcher.process_key_event(F, 0, CTRL | SHIFT).await;
+        assert!(matches!(output, ModeSwitcherReturn::Done(false)));
+    }
+
+    #[tokio::test]
+    async fn without_a_hotkey_ctrl_shift_f_goes_to_the_application() {
+        let switcher = in_pinyin_mode(None).await;
+
+        let output = switcher.process_key_event(F, 0, CTRL | SHIFT).await;
+        assert!(matches!(output, ModeSwitcherReturn::Done(false)));
+    }
+}
@@ -2,16 +2,16 @@ use std::sync::Arc;
 
 use tokio::sync::Mutex;
 
-use crate::{keys::Key, mode_switcher::KeyContent};
+use crate::mode_switcher::KeyContent;
 
-use super::ibus_proxy::IBusProxy;
+use super::ibus_proxy::EngineSignals;
 
 pub struct NumberService {
-    ibus: Arc<Mutex<IBusProxy>>,
+    ibus: Arc<Mutex<dyn EngineSignals>>,
 }
 
 impl NumberService {
-    pub fn new(ibus: Arc<Mutex<IBusProxy>>) -> NumberService {
+    pub fn new(ibus: Arc<Mutex<dyn EngineSignals>>) -> NumberService {
         NumberService { ibus }
     }
 
@@ -0,0 +1,294 @@
+use std::collections::HashSet;
+
+// Splits a run of pinyin letters into syllables, e.g. xianzai -> xian'zai. A typed ' is a hard
+// boundary. The result is only shown in the preedit: the backend gets the letters as typed, so it
+// can still split them differently (fangan as fang'an for 方案). Once a candidate is highlighted,
+// the letters it converts are split the way its pinyin is instead.
+
+static SYLLABLES: &str = "a ai an ang ao \
+    ba bai ban bang bao bei ben beng bi bian biao bie bin bing bo bu \
+    ca cai can cang cao ce cei cen ceng cha chai chan chang chao che chen cheng chi chong chou \
+    chu chua chuai chuan chuang chui chun chuo ci cong cou cu cuan cui cun cuo \
+    da dai dan dang dao de dei den deng di dia dian diao die ding diu dong dou du duan dui dun duo \
+    e ei en eng er \
+    fa fan fang fei fen feng fo fou fu \
+    ga gai gan gang gao ge gei gen geng gong gou gu gua guai guan guang gui gun guo \
+    ha hai han hang hao he hei hen heng hm hng hong hou hu hua huai huan huang hui hun huo \
+    ji jia jian jiang jiao jie jin jing jiong jiu ju juan jue jun \
+    ka kai kan kang kao ke kei ken keng kong kou ku kua kuai kuan kuang kui kun kuo \
+    la lai lan lang lao le lei leng li lia lian liang liao lie lin ling liu lo long lou lu luan \
+    lun luo lv lve \
+    m ma mai man mang mao me mei men meng mi mian miao mie min ming miu mo mou mu \
+    n na nai nan nang nao ne nei nen neng ng ni nian niang niao nie nin ning niu nong nou nu \
+    nuan nun nuo nv nve \
+    o ou \
+    pa pai pan pang pao pei pen peng pi pian piao pie pin ping po pou pu \
+    qi qia qian qiang qiao qie qin qing qiong qiu qu quan que qun \
+    ran rang rao re ren reng ri rong rou ru rua ruan rui run ruo \
+    sa sai san sang sao se sen seng sha shai shan shang shao she shei shen sheng shi shou shu \
+    shua shuai shuan shuang shui shun shuo si song sou su suan sui sun suo \
+    ta tai tan tang tao te teng ti tian tiao tie ting tong tou tu tuan tui tun tuo \
+    wa wai wan wang wei wen weng wo wu \
+    xi xia xian xiang xiao xie xin xing xiong xiu xu xuan xue xun \
+    ya yan yang yao ye yi yin ying yo yong you yu yuan yue yun \
+    za zai zan zang zao ze zei zen zeng zha zhai zhan zhang zhao zhe zhei zhen zheng zhi zhong \
+    zhou zhu zhua zhuai zhuan zhuang zhui zhun zhuo zi zong zou zu zuan zui zun zuo";
+
+// The longest syllable, zhuang, has six letters.
+const MAX_SYLLABLE_LEN: usize = 6;
+
+pub struct PinyinSegmenter {
+    syllables: HashSet<&'static str>,
+    // Every prefix of a syllable, so half-typed syllables (zh, xia for xiang) still segment.
+    prefixes: HashSet<String>,
+}
+
+// The segmented text and where each typed char ended up in it.
+#[derive(Debug, PartialEq)]
+pub struct Segmentation {
+    pub text: String,
+    // raw_to_text[i] is the index in text of the ith typed char.
+    raw_to_text: Vec<usize>,
+}
+
+impl Segmentation {
+    // The typed chars as they are, for input tools that aren't pinyin.
+    pub fn unsegmented(raw: &[char]) -> Segmentation {
+        Segmentation {
+            text: raw.iter().collect(),
+            raw_to_text: (0..raw.len()).collect(),
+        }
+    }
+
+    // Length in text of the first n typed chars, not counting a separator right after them.
+    pub fn text_len(&self, n: usize) -> usize {
+        match n {
+            0 => 0,
+            n => self.raw_to_text[std::cmp::min(n, self.raw_to_text.len()) - 1] + 1,
+        }
+    }
+}
+
+// Lower is better, compared field by field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+struct Cost {
+    // Letters that start no syllable at all.
+    unknown: usize,
+    // Segments that are only the start of a syllable.
+    incomplete: usize,
+    segments: usize,
+    // Syllables starting with a vowel, so fangan is fan'gan rather than fang'an.
+    vowel_initial: usize,
+}
+
+impl PinyinSegmenter {
+    pub fn new() -> PinyinSegmenter {
+        let syllables: HashSet<&'static str> = SYLLABLES.split_whitespace().collect();
+        let mut prefixes = HashSet::new();
+        for syllable in &syllables {
+            for end in 1..=syllable.len() {
+                prefixes.insert(syllable[..end].to_owned());
+            }
+        }
+
+        PinyinSegmenter {
+            syllables,
+            prefixes,
+        }
+    }
+
+    pub fn segment(&self, raw: &[char]) -> Segmentation {
+        let mut text = String::new();
+        let mut raw_to_text = Vec::with_capacity(raw.len());
+        let mut count = 0;
+
+        for (i, chunk) in raw.split(|c| *c == '\'').enumerate() {
+            if i > 0 {
+                // The typed '.
+                raw_to_text.push(count);
+                text.push('\'');
+                count += 1;
+            }
+            for (j, segment) in self.segment_chunk(chunk).iter().enumerate() {
+                if j > 0 {
+                    text.push('\'');
+                    count += 1;
+                }
+                for c in segment.iter() {
+                    raw_to_text.push(count);
+                    text.push(*c);
+                    count += 1;
+                }
+            }
+        }
+
+        Segmentation { text, raw_to_text }
+    }
+
+    // Like segment, but the first `converted` typed chars are split where the syllables of the
+    // candidate's annotation (e.g. "fang an") end. Falls back to segment if the annotation doesn't
+    // spell those chars.
+    pub fn segment_along(&self, raw: &[char], converted: usize, annotation: &str) -> Segmentation {
+        let converted = std::cmp::min(converted, raw.len());
+        let syllables: Vec<Vec<char>> = annotation
+            .split(|c: char| c.is_whitespace() || c == '\'')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_lowercase().chars().collect())
+            .collect();
+        let (head, tail) = raw.split_at(converted);
+        let letters: Vec<char> = head.iter().filter(|c| **c != '\'').cloned().collect();
+        if converted == 0 || syllables.concat() != letters {
+            return self.segment(raw);
+        }
+
+        // Raw indices a syllable of the annotation starts at.
+        let mut starts = Vec::new();
+        let mut i = 0;
+        for syllable in &syllables {
+            while head[i] == '\'' {
+                i += 1;
+            }
+            starts.push(i);
+            i += syllable.len();
+        }
+
+        let mut text = String::new();
+        let mut raw_to_text = Vec::with_capacity(raw.len());
+        for (i, c) in head.iter().enumerate() {
+            if i > 0 && starts.contains(&i) && head[i - 1] != '\'' {
+                text.push('\'');
+            }
+            raw_to_text.push(text.chars().count());
+            text.push(*c);
+        }
+        if tail.is_empty() {
+            return Segmentation { text, raw_to_text };
+        }
+
+        if tail[0] != '\'' && head[converted - 1] != '\'' {
+            text.push('\'');
+        }
+        let offset = text.chars().count();
+        let rest = self.segment(tail);
+        text.push_str(&rest.text);
+        raw_to_text.extend(rest.raw_to_text.iter().map(|i| i + offset));
+        Segmentation { text, raw_to_text }
+    }
+
+    // Cheapest split of a run of letters, found by dynamic programming over the end positions.
+    fn segment_chunk<'a>(&self, chunk: &'a [char]) -> Vec<&'a [char]> {
+        let n = chunk.len();
+        let mut best: Vec<Option<(Cost, usize)>> = vec![None; n + 1];
+        best[0] = Some((Cost::default(), 0));
+
+        for start in 0..n {
+            let (cost, _) = match best[start] {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            for end in start + 1..=std::cmp::min(n, start + MAX_SYLLABLE_LEN) {
+                let segment: String = chunk[start..end].iter().collect();
+                let mut next = Cost {
+                    segments: cost.segments + 1,
+                    ..cost
+                };
+                if self.syllables.contains(segment.as_str()) {
+                    // Complete syllable.
+                } else if self.prefixes.contains(&segment) {
+                    next.incomplete += 1;
+                } else if end == start + 1 {
+                    next.unknown += 1;
+                } else {
+                    continue;
+                }
+                if matches!(chunk[start], 'a' | 'e' | 'o') {
+                    next.vowel_initial += 1;
+                }
+
+                if best[end].is_none_or(|(c, _)| next < c) {
+                    best[end] = Some((next, start));
+                }
+            }
+        }
+
+        let mut segments = Vec::new();
+        let mut end = n;
+        while end > 0 {
+            let (_, start) = best[end].expect("Every position is reachable one letter at a time.");
+            segments.push(&chunk[start..end]);
+            end = start;
+        }
+        segments.reverse();
+        segments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(raw: &str) -> String {
+        let raw: Vec<char> = raw.chars().collect();
+        PinyinSegmenter::new().segment(&raw).text
+    }
+
+    // These pin what the preedit shows; queries are sent unsegmented.
+    #[test]
+    fn prefers_fewer_syllables() {
+        assert_eq!(segment("xian"), "xian");
+        assert_eq!(segment("nihao"), "ni'hao");
+        assert_eq!(segment("zhongguo"), "zhong'guo");
+        assert_eq!(segment("fangan"), "fan'gan");
+    }
+
+    #[test]
+    fn typed_apostrophe_is_a_hard_boundary() {
+        assert_eq!(segment("xi'an"), "xi'an");
+        assert_eq!(segment("fang'an"), "fang'an");
+    }
+
+    #[test]
+    fn keeps_half_typed_syllables() {
+        assert_eq!(segment("nihaozh"), "ni'hao'zh");
+        assert_eq!(segment("zhg"), "zh'g");
+    }
+
+    fn segment_along(raw: &str, converted: usize, annotation: &str) -> String {
+        let raw: Vec<char> = raw.chars().collect();
+        PinyinSegmenter::new().segment_along(&raw, converted, annotation).text
+    }
+
+    #[test]
+    fn follows_the_highlighted_candidate() {
+        assert_eq!(segment_along("fangan", 6, "fang an"), "fang'an");
+        assert_eq!(segment_along("fangan", 6, "fan gan"), "fan'gan");
+        assert_eq!(segment_along("fanganzh", 6, "fang an"), "fang'an'zh");
+        assert_eq!(segment_along("xi'anxian", 5, "xi an"), "xi'an'xian");
+    }
+
+    #[test]
+    fn ignores_an_annotation_that_doesnt_spell_the_letters() {
+        assert_eq!(segment_along("fangan", 6, ""), "fan'gan");
+        assert_eq!(segment_along("fangan", 6, "fang"), "fan'gan");
+        assert_eq!(segment_along("fangan", 0, "fang an"), "fan'gan");
+    }
+
+    #[test]
+    fn maps_positions_through_the_candidates_split() {
+        let raw: Vec<char> = "fanganzh".chars().collect();
+        let segmentation = PinyinSegmenter::new().segment_along(&raw, 6, "fang an");
+        assert_eq!(segmentation.text_len(4), 4);
+        assert_eq!(segmentation.text_len(5), 6);
+        assert_eq!(segmentation.text_len(7), 9);
+    }
+
+    #[test]
+    fn maps_between_typed_and_shown_positions() {
+        let raw: Vec<char> = "nihao".chars().collect();
+        let segmentation = PinyinSegmenter::new().segment(&raw);
+        assert_eq!(segmentation.text_len(2), 2);
+        assert_eq!(segmentation.text_len(3), 4);
+        assert_eq!(segmentation.text_len(5), 6);
+    }
+}
@@ -1,17 +1,27 @@
+use std::sync::Arc;
+
 use zbus::Connection;
 
-use crate::{dispatcher::Dispatcher, mode_switcher::{ModeSwitcher, ModeSwitcherReturn}};
+use crate::{candidate_backend::Backends, config::Config, dispatcher::Dispatcher, mode_switcher::{ModeSwitcher, ModeSwitcherReturn}, script_converter::ScriptConverter};
 
 pub struct Pipeline {
     mode_switcher: ModeSwitcher,
-    dispatcher: Dispatcher,
+    dispatcher: Arc<Dispatcher>,
 }
 
 impl Pipeline {
-    pub fn new(conn: &Connection) -> Pipeline {
+    pub fn new(
+        conn: &Connection,
+        path: &str,
+        backends: Backends,
+        converter: Option<Arc<ScriptConverter>>,
+        config: &Config,
+    ) -> Pipeline {
+        // Without conversion tables the shortcut would do nothing, so it is left to the application.
+        let toggle_script = converter.as_ref().and(config.conversion.toggle_hotkey());
         Pipeline {
-            mode_switcher: ModeSwitcher::new(),
-            dispatcher: Dispatcher::new(conn),
+            mode_switcher: ModeSwitcher::new(toggle_script),
+            dispatcher: Arc::new(Dispatcher::new(conn, path, backends, converter, config)),
         }
     }
 
@@ -27,10 +37,54 @@ impl Pipeline {
             ModeSwitcherReturn::Done(has_handled) => 
                         has_handled,
             ModeSwitcherReturn::SwitchMode => {
-                self.dispatcher.preedit_svc.clear().await;
-                self.dispatcher.candidate_svc.clear().await;
+                self.dispatcher.reset().await;
+                true
+            },
+            ModeSwitcherReturn::ToggleScript => {
+                self.dispatcher.toggle_traditional().await;
                 true
             },
         }
     }
+
+    pub async fn focus_in(&self) {
+        self.dispatcher.candidate_svc.register_properties().await;
+    }
+
+    pub async fn focus_out(&self) {
+        self.dispatcher.on_focus_out().await;
+    }
+
+    pub async fn reset(&self) {
+        self.dispatcher.reset().await;
+    }
+
+    pub async fn candidate_clicked(&self, index: u32) {
+        self.dispatcher.handle_click(index).await;
+    }
+
+    pub async fn page_up(&self) {
+        self.dispatcher.page_up().await;
+    }
+
+    pub async fn page_down(&self) {
+        self.dispatcher.page_down().await;
+    }
+
+    pub async fn cursor_up(&self) {
+        self.dispatcher.cursor_up().await;
+    }
+
+    pub async fn cursor_down(&self) {
+        self.dispatcher.cursor_down().await;
+    }
+
+    pub async fn property_activate(&self, name: &str) {
+        self.dispatcher.property_activate(name).await;
+    }
+
+    // The engine is going away; answers to queries still in flight are dropped.
+    pub fn destroy(&self) {
+        self.dispatcher.cancel_pending();
+    }
 }
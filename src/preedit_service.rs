@@ -1,62 +1,191 @@
 use std::sync::Arc;
 
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, MutexGuard};
 
-use super::ibus_proxy::IBusProxy;
+use super::{
+    ibus_proxy::EngineSignals,
+    ibus_variants::{IBusAttribute, IBusText},
+    pinyin_segmenter::{PinyinSegmenter, Segmentation},
+};
+
+// Colours of the converted part of the preedit.
+const CONVERTED_FG: u32 = 0x000000;
+const CONVERTED_BG: u32 = 0xc8dcf0;
 
 struct State {
     preedit: Vec<char>,
+    // Where the next letter goes, in chars.
+    cursor: usize,
+    // How many leading chars the highlighted candidate converts. Shown highlighted.
+    converted: usize,
+    // The highlighted candidate, shown in place of the converted chars if inline is on.
+    candidate: String,
+    // Its pinyin, e.g. "fang an", which says how the converted chars split into syllables.
+    annotation: String,
 }
 
 impl State {
     fn new() -> State {
         State {
             preedit: Vec::new(),
+            cursor: 0,
+            converted: 0,
+            candidate: String::new(),
+            annotation: String::new(),
         }
     }
 }
 
 pub struct PreeditService {
-    ibus: Arc<Mutex<IBusProxy>>,
+    ibus: Arc<Mutex<dyn EngineSignals>>,
     state: Mutex<State>,
+    inline: bool,
+    // Only for pinyin and only for display. Other input tools show the letters as typed.
+    segmenter: Option<PinyinSegmenter>,
 }
 
 impl PreeditService {
-    pub fn new(ibus: Arc<Mutex<IBusProxy>>) -> PreeditService {
+    pub fn new(ibus: Arc<Mutex<dyn EngineSignals>>, inline: bool, segment: bool) -> PreeditService {
         PreeditService {
             ibus,
             state: Mutex::new(State::new()),
+            inline,
+            segmenter: if segment {
+                Some(PinyinSegmenter::new())
+            } else {
+                None
+            },
+        }
+    }
+
+    // A typed ' between letters, marking a syllable boundary. Returns false where it would mean
+    // nothing: at the start or next to another '.
+    pub async fn push_boundary(&self) -> bool {
+        let state = self.state.lock().await;
+        let cursor = state.cursor;
+        let before = cursor.checked_sub(1).map(|i| state.preedit[i]);
+        let after = state.preedit.get(cursor).cloned();
+        if before.is_none() || before == Some('\'') || after == Some('\'') {
+            return false;
         }
+        drop(state);
+
+        self.push('\'').await;
+        true
     }
 
+    // Inserts at the cursor.
     pub async fn push(&self, c: char) {
         let mut state = self.state.lock().await;
-        state.preedit.push(c);
-        let preedit: String = state.preedit.iter().cloned().collect();
+        let cursor = state.cursor;
+        state.preedit.insert(cursor, c);
+        state.cursor += 1;
+        state.converted = 0;
+        state.candidate.clear();
+        state.annotation.clear();
 
-        drop(state);
-
-        self.ibus
-            .lock()
-            .await
-            .update_preedit_text(&preedit, preedit.len() as u32, true)
-            .await;
+        self.show(state).await;
     }
 
+    // Removes the char before the cursor, like Backspace.
     pub async fn pop(&self) -> Option<char> {
         let mut state = self.state.lock().await;
-        let popped = state.preedit.pop();
-        let preedit: String = state.preedit.iter().cloned().collect();
+        if state.cursor == 0 {
+            return None;
+        }
+        state.cursor -= 1;
+        let cursor = state.cursor;
+        let popped = state.preedit.remove(cursor);
+        state.converted = 0;
+        state.candidate.clear();
+        state.annotation.clear();
 
-        drop(state);
+        self.show(state).await;
 
-        self.ibus
-            .lock()
-            .await
-            .update_preedit_text(&preedit, preedit.len() as u32, true)
-            .await;
+        Some(popped)
+    }
+
+    // Removes the char after the cursor, like Delete.
+    pub async fn delete(&self) -> Option<char> {
+        let mut state = self.state.lock().await;
+        let cursor = state.cursor;
+        if cursor >= state.preedit.len() {
+            return None;
+        }
+        let deleted = state.preedit.remove(cursor);
+        state.converted = 0;
+        state.candidate.clear();
+        state.annotation.clear();
+
+        self.show(state).await;
+
+        Some(deleted)
+    }
+
+    // Cursor movement. Each returns false if the cursor was already there.
+    pub async fn move_left(&self) -> bool {
+        let mut state = self.state.lock().await;
+        if state.cursor == 0 {
+            return false;
+        }
+        state.cursor -= 1;
+        self.show(state).await;
+        true
+    }
+
+    pub async fn move_right(&self) -> bool {
+        let mut state = self.state.lock().await;
+        if state.cursor >= state.preedit.len() {
+            return false;
+        }
+        state.cursor += 1;
+        self.show(state).await;
+        true
+    }
+
+    pub async fn move_home(&self) -> bool {
+        let mut state = self.state.lock().await;
+        if state.cursor == 0 {
+            return false;
+        }
+        state.cursor = 0;
+        self.show(state).await;
+        true
+    }
+
+    pub async fn move_end(&self) -> bool {
+        let mut state = self.state.lock().await;
+        if state.cursor >= state.preedit.len() {
+            return false;
+        }
+        state.cursor = state.preedit.len();
+        self.show(state).await;
+        true
+    }
+
+    // Drops the first n characters, i.e. the pinyin covered by a partially committed candidate.
+    pub async fn consume(&self, n: usize) {
+        let mut state = self.state.lock().await;
+        let mut n = std::cmp::min(n, state.preedit.len());
+        // A boundary right after the consumed pinyin has nothing left to separate.
+        if state.preedit.get(n) == Some(&'\'') {
+            n += 1;
+        }
+        state.preedit.drain(..n);
+        state.cursor = state.cursor.saturating_sub(n);
+        state.converted = 0;
+        state.candidate.clear();
+        state.annotation.clear();
+
+        self.show(state).await;
+    }
 
-        popped
+    pub async fn len(&self) -> usize {
+        self.state.lock().await.preedit.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.state.lock().await.preedit.is_empty()
     }
 
     pub async fn to_string(&self) -> String {
@@ -68,6 +197,10 @@ impl PreeditService {
         
         let mut state = self.state.lock().await;
         state.preedit.clear();
+        state.cursor = 0;
+        state.converted = 0;
+        state.candidate.clear();
+        state.annotation.clear();
         // 获取空字符串用于 IBus 调用
         let preedit: String = state.preedit.iter().cloned().collect();
         drop(state);
@@ -76,7 +209,244 @@ impl PreeditService {
         self.ibus
             .lock()
             .await
-            .update_preedit_text(&preedit, 0, false) // 清空文本，光标位置0，设为不可见
+            .update_preedit_text(IBusText::from_str_ref(&preedit), 0, false) // 清空文本，光标位置0，设为不可见
             .await;
     }
+
+    // Marks the first n chars as converted by the highlighted candidate.
+    pub async fn set_converted(&self, n: usize, candidate: &str, annotation: &str) {
+        let mut state = self.state.lock().await;
+        let n = std::cmp::min(n, state.preedit.len());
+        if n == state.converted && candidate == state.candidate && annotation == state.annotation {
+            return;
+        }
+        state.converted = n;
+        state.candidate = candidate.to_owned();
+        state.annotation = annotation.to_owned();
+        self.show(state).await;
+    }
+
+    async fn show(&self, state: MutexGuard<'_, State>) {
+        let rendered = render(&state, self.segmentation(&state), self.inline);
+
+        drop(state);
+
+        self.ibus
+            .lock()
+            .await
+            .update_preedit_text(
+                IBusText::with_attributes(&rendered.text, rendered.attributes),
+                rendered.cursor as u32,
+                true,
+            )
+            .await;
+    }
+
+    // The converted chars are split like the highlighted candidate's pinyin, so fangan shows as
+    // fang'an while 方案 is highlighted.
+    fn segmentation(&self, state: &State) -> Segmentation {
+        match &self.segmenter {
+            Some(segmenter) => {
+                segmenter.segment_along(&state.preedit, state.converted, &state.annotation)
+            }
+            None => Segmentation::unsegmented(&state.preedit),
+        }
+    }
+}
+
+// The preedit as shown: its text, the cursor position in that text and its styling.
+struct Rendered {
+    text: String,
+    cursor: usize,
+    attributes: Vec<IBusAttribute>,
+}
+
+// The whole preedit is underlined, the converted part is also highlighted. Inline, the converted
+// part is replaced by the candidate unless the cursor is inside it, since then the user is editing
+// those letters.
+fn render(state: &State, segmentation: Segmentation, inline: bool) -> Rendered {
+    let text: Vec<char> = segmentation.text.chars().collect();
+    let cursor = segmentation.text_len(state.cursor);
+    let converted = segmentation.text_len(state.converted);
+    let inline = inline && state.converted > 0 && state.cursor >= state.converted;
+
+    let (preedit, cursor, converted) = if inline {
+        // The candidate replaces the converted pinyin and the separator after it.
+        let tail = if text.get(converted) == Some(&'\'') {
+            converted + 1
+        } else {
+            converted
+        };
+        let mut preedit = state.candidate.clone();
+        preedit.extend(&text[tail..]);
+        let shown = state.candidate.chars().count();
+        (preedit, shown + cursor.saturating_sub(tail), shown)
+    } else {
+        (segmentation.text, cursor, converted)
+    };
+    let len = preedit.chars().count();
+
+    let mut attributes = vec![IBusAttribute::underline(0, len)];
+    if converted > 0 {
+        attributes.push(IBusAttribute::foreground(CONVERTED_FG, 0, converted));
+        attributes.push(IBusAttribute::background(CONVERTED_BG, 0, converted));
+    }
+
+    Rendered {
+        text: preedit,
+        cursor,
+        attributes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ibus_proxy::recording::RecordingSignals;
+
+    fn service(inline: bool, segment: bool) -> (PreeditService, RecordingSignals) {
+        let signals = RecordingSignals::default();
+        let service = PreeditService::new(Arc::new(Mutex::new(signals.clone())), inline, segment);
+        (service, signals)
+    }
+
+    async fn type_str(service: &PreeditService, text: &str) {
+        for c in text.chars() {
+            service.push(c).await;
+        }
+    }
+
+    fn preedit_state(preedit: &str, cursor: usize, converted: usize, candidate: &str) -> State {
+        State {
+            preedit: preedit.chars().collect(),
+            cursor,
+            converted,
+            candidate: candidate.to_owned(),
+            annotation: String::new(),
+        }
+    }
+
+    fn segmented(state: &State) -> Segmentation {
+        PinyinSegmenter::new().segment(&state.preedit)
+    }
+
+    #[tokio::test]
+    async fn letters_go_in_at_the_cursor() {
+        let (service, signals) = service(false, false);
+        type_str(&service, "abc").await;
+        service.move_left().await;
+        service.move_left().await;
+        service.push('x').await;
+
+        assert_eq!(service.to_string().await, "axbc");
+        assert_eq!(signals.preedit(), ("axbc".to_owned(), 2));
+    }
+
+    #[tokio::test]
+    async fn home_end_and_delete() {
+        let (service, signals) = service(false, false);
+        type_str(&service, "abc").await;
+
+        assert!(service.move_home().await);
+        assert!(!service.move_left().await);
+        assert_eq!(service.delete().await, Some('a'));
+        assert_eq!(signals.preedit(), ("bc".to_owned(), 0));
+
+        assert!(service.move_end().await);
+        assert!(!service.move_right().await);
+        assert_eq!(service.delete().await, None);
+        assert_eq!(service.pop().await, Some('c'));
+        assert_eq!(signals.preedit(), ("b".to_owned(), 1));
+    }
+
+    #[tokio::test]
+    async fn consume_drops_the_committed_pinyin_and_its_separator() {
+        let (service, signals) = service(false, false);
+        type_str(&service, "ni'hao").await;
+        service.consume(2).await;
+
+        assert_eq!(signals.preedit(), ("hao".to_owned(), 3));
+
+        service.consume(1).await;
+        assert_eq!(signals.preedit(), ("ao".to_owned(), 2));
+    }
+
+    #[test]
+    fn cursor_follows_the_segmentation() {
+        let state = preedit_state("nihao", 3, 0, "");
+        let rendered = render(&state, segmented(&state), false);
+        assert_eq!(rendered.text, "ni'hao");
+        assert_eq!(rendered.cursor, 4);
+
+        // A typed separator isn't doubled.
+        let state = preedit_state("xi'an", 5, 0, "");
+        let rendered = render(&state, segmented(&state), false);
+        assert_eq!(rendered.text, "xi'an");
+        assert_eq!(rendered.cursor, 5);
+    }
+
+    #[tokio::test]
+    async fn converted_pinyin_is_split_like_the_candidate() {
+        let (service, signals) = service(false, true);
+        type_str(&service, "fangan").await;
+        assert_eq!(signals.preedit().0, "fan'gan");
+
+        service.set_converted(6, "方案", "fang an").await;
+        assert_eq!(signals.preedit(), ("fang'an".to_owned(), 7));
+    }
+
+    #[test]
+    fn converted_part_is_highlighted_within_the_underline() {
+        let state = preedit_state("nihao", 5, 2, "你");
+        let rendered = render(&state, segmented(&state), false);
+
+        assert_eq!(rendered.text, "ni'hao");
+        assert_eq!(
+            rendered.attributes,
+            [
+                IBusAttribute::underline(0, 6),
+                IBusAttribute::foreground(CONVERTED_FG, 0, 2),
+                IBusAttribute::background(CONVERTED_BG, 0, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn nothing_converted_is_only_underlined() {
+        let state = preedit_state("nihao", 5, 0, "");
+        let rendered = render(&state, segmented(&state), false);
+
+        assert_eq!(rendered.attributes, [IBusAttribute::underline(0, 6)]);
+    }
+
+    #[test]
+    fn inline_candidate_replaces_the_converted_pinyin() {
+        let state = preedit_state("nihao", 5, 2, "你");
+        let rendered = render(&state, segmented(&state), true);
+
+        assert_eq!(rendered.text, "你hao");
+        assert_eq!(rendered.cursor, 4);
+        assert_eq!(
+            rendered.attributes,
+            [
+                IBusAttribute::underline(0, 4),
+                IBusAttribute::foreground(CONVERTED_FG, 0, 1),
+                IBusAttribute::background(CONVERTED_BG, 0, 1),
+            ]
+        );
+
+        let state = preedit_state("nihao", 5, 5, "你好");
+        let rendered = render(&state, segmented(&state), true);
+        assert_eq!(rendered.text, "你好");
+        assert_eq!(rendered.cursor, 2);
+    }
+
+    #[test]
+    fn inline_candidate_gives_way_while_editing_the_converted_pinyin() {
+        let state = preedit_state("nihao", 1, 2, "你");
+        let rendered = render(&state, segmented(&state), true);
+
+        assert_eq!(rendered.text, "ni'hao");
+        assert_eq!(rendered.cursor, 1);
+    }
 }
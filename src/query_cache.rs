@@ -0,0 +1,242 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    candidate::Candidate,
+    config::{cache_dir, CacheConfig},
+};
+
+// On-disk cache of backend answers, keyed by (input tool, depth, preedit).
+//
+// Two trees are kept: `entries` maps a key to its candidates, `by_age` maps
+// (stored_at, key) to nothing so the oldest entries can be evicted without scanning everything.
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    stored_at: u64,
+    candidates: Vec<Candidate>,
+}
+
+pub struct QueryCache {
+    entries: sled::Tree,
+    by_age: sled::Tree,
+    ttl: Duration,
+    max_entries: usize,
+    len: AtomicUsize,
+}
+
+impl QueryCache {
+    pub fn open(config: &CacheConfig) -> Option<QueryCache> {
+        if !config.enabled {
+            return None;
+        }
+
+        let path = cache_dir()?.join("query-cache");
+        let db = match sled::open(&path) {
+            Ok(db) => db,
+            Err(e) => {
+                println!("Failed to open the query cache at {:?}: {}", path, e);
+                return None;
+            }
+        };
+
+        QueryCache::in_db(&db, config)
+    }
+
+    fn in_db(db: &sled::Db, config: &CacheConfig) -> Option<QueryCache> {
+        let entries = db.open_tree("entries").ok()?;
+        let by_age = db.open_tree("by_age").ok()?;
+        let len = AtomicUsize::new(entries.len());
+
+        Some(QueryCache {
+            entries,
+            by_age,
+            ttl: Duration::from_secs(config.ttl_secs),
+            max_entries: config.max_entries,
+            len,
+        })
+    }
+
+    // Returns the cached candidates if they are younger than the TTL.
+    pub fn get(&self, preedit: &str, depth: usize, input_tool: &str) -> Option<Vec<Candidate>> {
+        let key = make_key(preedit, depth, input_tool);
+        let entry = self.read(&key)?;
+
+        if now().saturating_sub(entry.stored_at) > self.ttl.as_secs() {
+            return None;
+        }
+
+        Some(entry.candidates)
+    }
+
+    // Returns the cached candidates regardless of their age. Used when the backend is unreachable,
+    // where a stale answer beats no answer.
+    pub fn get_stale(
+        &self,
+        preedit: &str,
+        depth: usize,
+        input_tool: &str,
+    ) -> Option<Vec<Candidate>> {
+        let key = make_key(preedit, depth, input_tool);
+        self.read(&key).map(|entry| entry.candidates)
+    }
+
+    pub fn insert(&self, preedit: &str, depth: usize, input_tool: &str, candidates: &[Candidate]) {
+        self.insert_at(now(), preedit, depth, input_tool, candidates);
+    }
+
+    fn insert_at(
+        &self,
+        stored_at: u64,
+        preedit: &str,
+        depth: usize,
+        input_tool: &str,
+        candidates: &[Candidate],
+    ) {
+        let key = make_key(preedit, depth, input_tool);
+        let entry = Entry {
+            stored_at,
+            candidates: candidates.to_vec(),
+        };
+        let value = match bincode::serialize(&entry) {
+            Ok(value) => value,
+            Err(e) => {
+                println!("Failed to serialize cache entry: {}", e);
+                return;
+            }
+        };
+
+        match self.entries.insert(&key, value) {
+            Ok(Some(old)) => {
+                if let Ok(old) = bincode::deserialize::<Entry>(&old) {
+                    let _ = self.by_age.remove(age_key(old.stored_at, &key));
+                }
+            }
+            Ok(None) => {
+                self.len.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                println!("Failed to write to the query cache: {}", e);
+                return;
+            }
+        }
+        let _ = self.by_age.insert(age_key(entry.stored_at, &key), &[]);
+
+        self.evict();
+    }
+
+    fn read(&self, key: &[u8]) -> Option<Entry> {
+        let value = self.entries.get(key).ok()??;
+        bincode::deserialize(&value).ok()
+    }
+
+    fn evict(&self) {
+        while self.len.load(Ordering::Relaxed) > self.max_entries {
+            let (age_key, _) = match self.by_age.pop_min() {
+                Ok(Some(oldest)) => oldest,
+                _ => return,
+            };
+            if let Ok(Some(_)) = self.entries.remove(&age_key[8..]) {
+                self.len.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn make_key(preedit: &str, depth: usize, input_tool: &str) -> Vec<u8> {
+    format!("{}\0{}\0{}", input_tool, depth, preedit).into_bytes()
+}
+
+// Big-endian so that sled's byte ordering is also chronological ordering.
+fn age_key(stored_at: u64, key: &[u8]) -> Vec<u8> {
+    let mut age_key = stored_at.to_be_bytes().to_vec();
+    age_key.extend_from_slice(key);
+    age_key
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(ttl_secs: u64, max_entries: usize) -> QueryCache {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let config = CacheConfig {
+            enabled: true,
+            ttl_secs,
+            max_entries,
+        };
+        QueryCache::in_db(&db, &config).unwrap()
+    }
+
+    fn candidates(word: &str) -> Vec<Candidate> {
+        vec![Candidate {
+            word: word.to_owned(),
+            annotation: "".to_owned(),
+            matched_len: None,
+        }]
+    }
+
+    fn word(candidates: Option<Vec<Candidate>>) -> Option<String> {
+        candidates.map(|c| c[0].word.clone())
+    }
+
+    #[test]
+    fn expires_after_the_ttl() {
+        let cache = cache(60, 10);
+        cache.insert_at(now() - 30, "nihao", 11, "pinyin", &candidates("你好"));
+        cache.insert_at(now() - 90, "shijie", 11, "pinyin", &candidates("世界"));
+
+        assert_eq!(word(cache.get("nihao", 11, "pinyin")), Some("你好".to_owned()));
+        assert_eq!(word(cache.get("shijie", 11, "pinyin")), None);
+        assert_eq!(word(cache.get("nihao", 21, "pinyin")), None);
+        assert_eq!(word(cache.get("nihao", 11, "jyutping")), None);
+    }
+
+    #[test]
+    fn stale_entries_are_still_available() {
+        let cache = cache(60, 10);
+        cache.insert_at(now() - 90, "shijie", 11, "pinyin", &candidates("世界"));
+
+        assert_eq!(word(cache.get_stale("shijie", 11, "pinyin")), Some("世界".to_owned()));
+        assert_eq!(word(cache.get_stale("nihao", 11, "pinyin")), None);
+    }
+
+    #[test]
+    fn overwriting_replaces_the_age_entry() {
+        let cache = cache(60, 10);
+        cache.insert_at(100, "nihao", 11, "pinyin", &candidates("拟好"));
+        cache.insert_at(200, "nihao", 11, "pinyin", &candidates("你好"));
+
+        assert_eq!(cache.len.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.by_age.len(), 1);
+        let (age_key, _) = cache.by_age.first().unwrap().unwrap();
+        assert_eq!(age_key.as_ref(), self::age_key(200, &make_key("nihao", 11, "pinyin")));
+        assert_eq!(word(cache.get_stale("nihao", 11, "pinyin")), Some("你好".to_owned()));
+    }
+
+    #[test]
+    fn evicts_the_oldest_entries() {
+        let cache = cache(60, 2);
+        cache.insert_at(300, "c", 11, "pinyin", &candidates("c"));
+        cache.insert_at(100, "a", 11, "pinyin", &candidates("a"));
+        cache.insert_at(200, "b", 11, "pinyin", &candidates("b"));
+
+        assert_eq!(cache.len.load(Ordering::Relaxed), 2);
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.by_age.len(), 2);
+        assert_eq!(word(cache.get_stale("a", 11, "pinyin")), None);
+        assert_eq!(word(cache.get_stale("b", 11, "pinyin")), Some("b".to_owned()));
+        assert_eq!(word(cache.get_stale("c", 11, "pinyin")), Some("c".to_owned()));
+    }
+}
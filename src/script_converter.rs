@@ -0,0 +1,127 @@
+use std::{collections::HashMap, path::Path};
+
+use crate::config::{config_dir, ConversionConfig};
+
+// Simplified -> traditional conversion driven by OpenCC-style text tables: STPhrases.txt and
+// STCharacters.txt, one `<simplified>\t<traditional> [alternatives...]` entry per line. Phrases
+// are matched greedily (longest first) before falling back to single characters.
+pub struct ScriptConverter {
+    phrases: HashMap<String, String>,
+    chars: HashMap<char, String>,
+    max_phrase_len: usize,
+}
+
+impl ScriptConverter {
+    pub fn load(config: &ConversionConfig) -> Option<ScriptConverter> {
+        let dir = match &config.table_dir {
+            Some(dir) => dir.clone(),
+            None => config_dir()?.join("opencc"),
+        };
+
+        let chars_table = match read_table(&dir.join("STCharacters.txt")) {
+            Some(table) => table,
+            None => {
+                // Only worth a message if the user pointed us somewhere.
+                if config.table_dir.is_some() {
+                    println!("Failed to read STCharacters.txt from {:?}.", dir);
+                }
+                return None;
+            }
+        };
+        let phrases = read_table(&dir.join("STPhrases.txt")).unwrap_or_default();
+
+        Some(ScriptConverter::from_tables(phrases, chars_table))
+    }
+
+    fn from_tables(
+        phrases: HashMap<String, String>,
+        chars_table: HashMap<String, String>,
+    ) -> ScriptConverter {
+        let chars = chars_table
+            .into_iter()
+            .filter_map(|(k, v)| {
+                let mut k_chars = k.chars();
+                match (k_chars.next(), k_chars.next()) {
+                    (Some(c), None) => Some((c, v)),
+                    _ => None,
+                }
+            })
+            .collect();
+        let max_phrase_len = phrases.keys().map(|k| k.chars().count()).max().unwrap_or(0);
+
+        ScriptConverter {
+            phrases,
+            chars,
+            max_phrase_len,
+        }
+    }
+
+    pub fn convert(&self, text: &str) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        let mut converted = String::with_capacity(text.len());
+        let mut i = 0;
+
+        'outer: while i < chars.len() {
+            let longest = std::cmp::min(self.max_phrase_len, chars.len() - i);
+            for len in (2..=longest).rev() {
+                let phrase: String = chars[i..i + len].iter().collect();
+                if let Some(traditional) = self.phrases.get(&phrase) {
+                    converted.push_str(traditional);
+                    i += len;
+                    continue 'outer;
+                }
+            }
+
+            match self.chars.get(&chars[i]) {
+                Some(traditional) => converted.push_str(traditional),
+                None => converted.push(chars[i]),
+            }
+            i += 1;
+        }
+
+        converted
+    }
+}
+
+fn read_table(path: &Path) -> Option<HashMap<String, String>> {
+    let text = std::fs::read_to_string(path).ok()?;
+    Some(parse_table(&text))
+}
+
+fn parse_table(text: &str) -> HashMap<String, String> {
+    let mut table = HashMap::new();
+    for line in text.lines() {
+        let mut fields = line.split('\t');
+        let (key, values) = match (fields.next(), fields.next()) {
+            (Some(key), Some(values)) => (key, values),
+            _ => continue,
+        };
+        // Several candidates may be listed; the first one is the preferred.
+        if let Some(value) = values.split(' ').next() {
+            table.insert(key.to_owned(), value.to_owned());
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn converter() -> ScriptConverter {
+        ScriptConverter::from_tables(
+            parse_table("头发\t頭髮\n"),
+            parse_table("发\t發 髮\n头\t頭\n们\t們\n"),
+        )
+    }
+
+    #[test]
+    fn converts_characters_using_the_first_alternative() {
+        assert_eq!(converter().convert("我们发"), "我們發");
+    }
+
+    #[test]
+    fn prefers_phrases_over_characters() {
+        assert_eq!(converter().convert("头发们"), "頭髮們");
+    }
+}
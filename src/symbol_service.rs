@@ -2,16 +2,16 @@ use std::sync::Arc;
 
 use tokio::sync::Mutex;
 
-use crate::{keys::Key, mode_switcher::KeyContent};
+use crate::mode_switcher::KeyContent;
 
-use super::ibus_proxy::IBusProxy;
+use super::ibus_proxy::EngineSignals;
 
 pub struct SymbolService {
-    pub(crate) ibus: Arc<Mutex<IBusProxy>>,
+    pub(crate) ibus: Arc<Mutex<dyn EngineSignals>>,
 }
 
 impl SymbolService {
-    pub fn new(ibus: Arc<Mutex<IBusProxy>>) -> SymbolService {
+    pub fn new(ibus: Arc<Mutex<dyn EngineSignals>>) -> SymbolService {
         SymbolService { ibus }
     }
 
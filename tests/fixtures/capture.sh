@@ -0,0 +1,12 @@
+#!/bin/bash
+
+# Saves real responses of the Google Input Tools endpoint to captured/, for the ignored tests in
+# src/cloud_pinyin_client.rs. nihaoshijie is long enough for candidates that cover only part of it,
+# so its response carries matched_length.
+
+cd "$(dirname "$0")/captured" || exit 1
+
+for text in nihao shijie nihaoshijie; do
+    curl -sSf "https://inputtools.google.com/request?text=$text&itc=zh-t-i0-pinyin&num=11&cp=0&cs=1&ie=utf-8&oe=utf-8&app=demopage" \
+        > "$text.json" || exit 1
+done